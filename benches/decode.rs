@@ -0,0 +1,63 @@
+extern crate bytes;
+extern crate criterion;
+extern crate stomp;
+extern crate tokio_io;
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use stomp::codec::Codec;
+use tokio_io::codec::Decoder;
+
+const CHUNK_SIZE: usize = 1460;
+
+fn message_frame(body_size: usize, with_content_length: bool) -> Vec<u8> {
+    let mut frame =
+        b"MESSAGE\nsubscription:stomp-rs/0\nmessage-id:1\ndestination:/queue/bench\n".to_vec();
+    if with_content_length {
+        frame.extend(format!("content-length:{}\n", body_size).as_bytes());
+    }
+    frame.extend(b"\n");
+    frame.extend(vec![b'x'; body_size]);
+    frame.push(0);
+    frame
+}
+
+// Feeds the frame to the decoder one TCP segment at a time, the way
+// `Framed` does when reading from the socket.
+fn decode_in_chunks(bytes: &[u8]) {
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::new();
+    let mut frames = 0;
+    for chunk in bytes.chunks(CHUNK_SIZE) {
+        buffer.extend_from_slice(chunk);
+        while codec.decode(&mut buffer).unwrap().is_some() {
+            frames += 1;
+        }
+    }
+    assert_eq!(frames, 1);
+}
+
+fn decode_large_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_large_frame");
+    group.sample_size(10);
+    for &megabytes in &[1, 4, 16] {
+        let body_size = megabytes * 1024 * 1024;
+        group.throughput(Throughput::Bytes(body_size as u64));
+        let with_length = message_frame(body_size, true);
+        group.bench_with_input(
+            BenchmarkId::new("content-length", megabytes),
+            &with_length,
+            |b, bytes| b.iter(|| decode_in_chunks(bytes)),
+        );
+        let without_length = message_frame(body_size, false);
+        group.bench_with_input(
+            BenchmarkId::new("nul-terminated", megabytes),
+            &without_length,
+            |b, bytes| b.iter(|| decode_in_chunks(bytes)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode_large_frames);
+criterion_main!(benches);
@@ -0,0 +1,285 @@
+use crate::error::Error;
+use crate::frame::{Frame, Transmission};
+use futures::executor::{self, Notify};
+use futures::sync::mpsc;
+use futures::{task, Async, AsyncSink, Poll, Sink, Stream};
+use std::cmp::min;
+use std::fmt;
+use std::io::{self, Read};
+use tokio_io::AsyncRead;
+
+// Number of chunks which may be waiting in a `BodyReader` before the session
+// stops reading from the connection.
+const INBOUND_CHUNKS: usize = 16;
+const OUTBOUND_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams the bodies of received MESSAGE frames whose `content-length` is at
+/// least the given number of bytes, delivering them as
+/// `SessionEvent::StreamedMessage` instead of buffering them.
+#[derive(Clone, Copy)]
+pub struct StreamLargeBodies(pub usize);
+
+/// The body of a streamed MESSAGE frame. Chunks are delivered while the
+/// `Session` is being polled; the session stops reading from the connection
+/// while the reader is not keeping up. Reads fail with `WouldBlock` until the
+/// next chunk has arrived; outside of a task, nothing is woken when it does,
+/// so the read should be retried after polling the `Session`.
+pub struct BodyReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    position: usize,
+    remaining: usize,
+}
+
+impl BodyReader {
+    pub(crate) fn new(content_length: usize) -> (BodySender, BodyReader) {
+        let (sender, chunks) = mpsc::channel(INBOUND_CHUNKS);
+        let reader = BodyReader {
+            chunks,
+            current: Vec::new(),
+            position: 0,
+            remaining: content_length,
+        };
+        let sender = BodySender {
+            sender: Some(sender),
+            pending: None,
+        };
+        (sender, reader)
+    }
+
+    /// The number of body bytes not yet read.
+    pub fn remaining(&self) -> usize {
+        self.remaining + self.current.len() - self.position
+    }
+
+    fn poll_chunk(&mut self) -> Poll<Option<Vec<u8>>, ()> {
+        if task::is_in_task() {
+            return self.chunks.poll();
+        }
+        executor::spawn(&mut self.chunks).poll_stream_notify(&&NoNotify, 0)
+    }
+}
+
+// Polls the chunks when the reader is used outside of a task
+struct NoNotify;
+
+impl Notify for NoNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            match self.poll_chunk() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    self.remaining -= chunk.len();
+                    self.current = chunk;
+                    self.position = 0;
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    if self.remaining > 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection lost before the end of the message body",
+                        ));
+                    }
+                    return Ok(0);
+                }
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        let count = min(buf.len(), self.current.len() - self.position);
+        buf[..count].copy_from_slice(&self.current[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl AsyncRead for BodyReader {}
+
+// The session's end of a `BodyReader`.
+pub(crate) struct BodySender {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    pending: Option<Vec<u8>>,
+}
+
+impl BodySender {
+    // A sender for a body nobody is interested in.
+    pub fn discard() -> BodySender {
+        BodySender {
+            sender: None,
+            pending: None,
+        }
+    }
+
+    pub fn push(&mut self, chunk: Vec<u8>) {
+        self.pending = Some(chunk);
+        self.poll_ready();
+    }
+
+    // Tries to hand over the pending chunk, returning whether the reader is
+    // ready to accept another one.
+    pub fn poll_ready(&mut self) -> bool {
+        let chunk = match self.pending.take() {
+            Some(chunk) => chunk,
+            None => return true,
+        };
+        let sent = match self.sender {
+            Some(ref mut sender) => sender.start_send(chunk),
+            None => return true,
+        };
+        match sent {
+            Ok(AsyncSink::Ready) => true,
+            Ok(AsyncSink::NotReady(chunk)) => {
+                self.pending = Some(chunk);
+                false
+            }
+            Err(_) => {
+                debug!("BodyReader dropped, discarding the rest of the body");
+                self.sender = None;
+                true
+            }
+        }
+    }
+}
+
+// A SEND frame whose body is read from an `AsyncRead` as it is written.
+pub(crate) struct OutboundBody {
+    head: Option<Frame>,
+    reader: Box<dyn AsyncRead>,
+    remaining: u64,
+    pending: Option<Transmission>,
+    finished: bool,
+}
+
+impl OutboundBody {
+    pub fn new(frame: Frame, reader: Box<dyn AsyncRead>, content_length: u64) -> OutboundBody {
+        OutboundBody {
+            head: Some(frame),
+            reader,
+            remaining: content_length,
+            pending: None,
+            finished: false,
+        }
+    }
+
+    fn next_transmission(&mut self) -> Poll<Option<Transmission>, Error> {
+        if let Some(transmission) = self.pending.take() {
+            return Ok(Async::Ready(Some(transmission)));
+        }
+        if let Some(frame) = self.head.take() {
+            return Ok(Async::Ready(Some(Transmission::FrameHead(frame))));
+        }
+        if self.finished {
+            return Ok(Async::Ready(None));
+        }
+        if self.remaining == 0 {
+            self.finished = true;
+            return Ok(Async::Ready(Some(Transmission::BodyEnd)));
+        }
+        let mut chunk = vec![0; min(self.remaining, OUTBOUND_CHUNK_SIZE as u64) as usize];
+        match self.reader.read(&mut chunk) {
+            Ok(0) => Err(Error::InvalidUsage(format!(
+                "message body ended {} bytes short of its content-length",
+                self.remaining
+            ))),
+            Ok(count) => {
+                chunk.truncate(count);
+                self.remaining -= count as u64;
+                Ok(Async::Ready(Some(Transmission::BodyChunk(chunk))))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Writes as much of the frame as the reader and the sink allow,
+    // resolving once the terminating NUL has been handed to the sink.
+    pub fn poll_send<S>(&mut self, sink: &mut S) -> Poll<(), Error>
+    where
+        S: Sink<SinkItem = Transmission, SinkError = Error>,
+    {
+        loop {
+            let transmission = match self.next_transmission()? {
+                Async::Ready(Some(transmission)) => transmission,
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if let AsyncSink::NotReady(transmission) = sink.start_send(transmission)? {
+                self.pending = Some(transmission);
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+pub(crate) enum Outbound {
+    Transmission(Transmission),
+    Streamed(OutboundBody),
+}
+
+impl fmt::Debug for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outbound::Transmission(ref transmission) => transmission.fmt(f),
+            Outbound::Streamed(ref body) => write!(f, "Streamed({:?})", body.head),
+        }
+    }
+}
+
+#[test]
+fn body_reader_receives_chunks() {
+    use futures::future::{lazy, Future};
+
+    lazy(|| -> ::std::result::Result<(), ()> {
+        let (mut sender, mut reader) = BodyReader::new(5);
+        let mut buf = [0; 8];
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        sender.push(b"hel".to_vec());
+        sender.push(b"lo".to_vec());
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(reader.read(&mut buf[3..]).unwrap(), 2);
+        assert_eq!(&buf[..5], b"hello");
+        drop(sender);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        Ok(())
+    })
+    .wait()
+    .unwrap();
+}
+
+#[test]
+fn body_reader_reports_truncated_body() {
+    use futures::future::{lazy, Future};
+
+    lazy(|| -> ::std::result::Result<(), ()> {
+        let (mut sender, mut reader) = BodyReader::new(5);
+        let mut buf = [0; 8];
+        sender.push(b"hel".to_vec());
+        drop(sender);
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        Ok(())
+    })
+    .wait()
+    .unwrap();
+}
+
+#[test]
+fn body_reader_outside_of_a_task() {
+    let (mut sender, mut reader) = BodyReader::new(2);
+    let mut buf = [0; 2];
+    assert_eq!(
+        reader.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    sender.push(b"hi".to_vec());
+    assert_eq!(reader.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf, b"hi");
+}
@@ -1,15 +1,20 @@
+use crate::error::Error;
+use crate::escape::{self, DecodeMode};
 use crate::frame::Command;
 use crate::frame::{Frame, Transmission};
-use crate::header::{Header, HeaderList};
+use crate::header::{ContentLength, Header, HeaderList};
 use bytes::BytesMut;
 use nom::IResult;
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::str::from_utf8;
 use tokio_io::codec::{Decoder, Encoder};
 
 use nom::branch::alt;
-use nom::bytes::streaming::{is_a, tag};
-use nom::character::complete::{anychar, line_ending};
-use nom::combinator::{complete, map};
-use nom::multi::{many0, many1, many_till};
+use nom::bytes::streaming::{tag, take_till};
+use nom::character::streaming::line_ending;
+use nom::combinator::map;
 
 fn parse_server_command(i: &[u8]) -> IResult<&[u8], Command> {
     alt((
@@ -20,6 +25,23 @@ fn parse_server_command(i: &[u8]) -> IResult<&[u8], Command> {
     ))(i)
 }
 
+// The frames the client sends, e.g. read back from a spool
+fn parse_sent_command(i: &[u8]) -> IResult<&[u8], Command> {
+    alt((
+        map(tag("SEND"), |_| Command::Send),
+        map(tag("SUBSCRIBE"), |_| Command::Subscribe),
+        map(tag("UNSUBSCRIBE"), |_| Command::Unsubscribe),
+        map(tag("BEGIN"), |_| Command::Begin),
+        map(tag("COMMIT"), |_| Command::Commit),
+        map(tag("ABORT"), |_| Command::Abort),
+        map(tag("ACK"), |_| Command::Ack),
+        map(tag("NACK"), |_| Command::Nack),
+        map(tag("DISCONNECT"), |_| Command::Disconnect),
+        map(tag("CONNECT"), |_| Command::Connect),
+        map(tag("STOMP"), |_| Command::Stomp),
+    ))(i)
+}
+
 /*
 named!(parse_server_command(&[u8]) -> Command,
        alt!(
@@ -31,173 +53,614 @@ named!(parse_server_command(&[u8]) -> Command,
 );
 */
 
-fn parse_header_character(i: &[u8]) -> IResult<&[u8], char> {
-    alt((
-        complete(map(tag("\\n"), |_| '\n')),
-        complete(map(tag("\\r"), |_| '\r')),
-        complete(map(tag("\\c"), |_| ':')),
-        complete(map(tag("\\\\"), |_| '\\')),
-        anychar,
-    ))(i)
+// Splits a header line into its still escaped key and value.
+fn parse_header(i: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (i, key) = take_till(|b| b == b':' || b == b'\r' || b == b'\n')(i)?;
+    let (i, _) = tag(":")(i)?;
+    let (i, value) = take_till(|b| b == b'\r' || b == b'\n')(i)?;
+    let (i, _) = line_ending(i)?;
+    Ok((i, (key, value)))
 }
 
-/*
-named!(parse_header_character(&[u8]) -> char,
-       alt!(
-           complete!(map!(tag!("\\n"), |_| '\n')) |
-           complete!(map!(tag!("\\r"), |_| '\r')) |
-           complete!(map!(tag!("\\c"), |_| ':')) |
-           complete!(map!(tag!("\\\\"), |_| '\\')) |
-           anychar
-       )
-);
-*/
-
-fn parse_header(i: &[u8]) -> IResult<&[u8], Header> {
-    let (i, k) = many_till(parse_header_character, is_a(":\r\n"))(i)?;
-    let (i, v) = many_till(parse_header_character, is_a("\r\n"))(i)?;
+fn parse_command_line(i: &[u8], sent: bool) -> IResult<&[u8], Command> {
+    let (i, command) = if sent {
+        parse_sent_command(i)?
+    } else {
+        parse_server_command(i)?
+    };
     let (i, _) = line_ending(i)?;
-    Ok((
-        i,
-        Header::new_raw(
-            k.0.into_iter().collect::<String>(),
-            v.0.into_iter().collect::<String>(),
-        ),
-    ))
+    Ok((i, command))
 }
 
-/*
-named!(parse_header(&[u8]) -> Header,
-       map!(
-           do_parse!(
-               k: flat_map!(is_not!(":\r\n"), many1!(parse_header_character)) >>
-               tag!(":") >>
-               v: flat_map!(is_not!("\r\n"), many1!(parse_header_character))>>
-               line_ending >>
-               (k, v)
-           ),
-           |(k, v)| {
-               Header::new_raw(k.into_iter().collect::<String>(), v.into_iter().collect::<String>())
-           }
-       )
-);
-*/
-fn get_body<'a, 'b>(bytes: &'a [u8], headers: &'b [Header]) -> ::nom::IResult<&'a [u8], &'a [u8]> {
-    let mut content_length = None;
-    for header in headers {
-        if header.0 == "content-length" {
-            trace!("found content-length header");
-            match header.1.parse::<u32>() {
-                Ok(value) => content_length = Some(value),
-                Err(error) => warn!("failed to parse content-length header: {}", error),
-            }
+/// Upper bounds enforced by `Codec` while decoding, protecting against a
+/// misbehaving broker sending unbounded frames.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLimits {
+    /// Maximum size in bytes of a complete frame, including its body.
+    pub max_frame_size: usize,
+    /// Maximum number of headers in a single frame.
+    pub max_headers: usize,
+    /// Maximum length in bytes of the command line or a single header line.
+    pub max_header_length: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> FrameLimits {
+        FrameLimits {
+            max_frame_size: 64 * 1024 * 1024,
+            max_headers: 1000,
+            max_header_length: 64 * 1024,
         }
     }
-    if let Some(content_length) = content_length {
-        trace!("using content-length header: {}", content_length);
-        take!(bytes, content_length)
-    } else {
-        trace!("using many0 method to parse body");
-        map!(bytes, many0!(is_not!("\0")), |body| {
-            if body.is_empty() {
-                &[]
-            } else {
-                body.into_iter().nth(0).unwrap()
+}
+
+/// The limit that caused `Codec` to reject a frame, reported as
+/// `Error::LimitExceeded`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitExceeded {
+    FrameSize(usize),
+    HeaderCount(usize),
+    HeaderLength(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitExceeded::FrameSize(limit) => write!(f, "frame exceeds {} bytes", limit),
+            LimitExceeded::HeaderCount(limit) => write!(f, "frame has more than {} headers", limit),
+            LimitExceeded::HeaderLength(limit) => {
+                write!(f, "header line exceeds {} bytes", limit)
             }
-        })
+        }
     }
 }
-fn parse_frame(i: &[u8]) -> IResult<&[u8], Frame> {
-    let (i, cmd) = parse_server_command(i)?;
-    let (i, _) = line_ending(i)?;
-    let (i, headers) = many0(parse_header)(i)?;
-    let (i, _) = line_ending(i)?;
-    let (i, body) = get_body(i, &headers)?;
-    let (i, _) = tag("\0")(i)?;
-
-    Ok((
-        i,
-        Frame {
-            command: cmd,
-            headers: HeaderList { headers },
-            body: body.into(),
-        },
-    ))
-}
-
-named!(parse_frame2(&[u8]) -> Frame,
-       map!(
-           do_parse!(
-               cmd: parse_server_command >>
-               line_ending >>
-               headers: many0!(parse_header) >>
-               line_ending >>
-               body: call!(get_body, &headers) >>
-               tag!("\0") >>
-               (cmd, headers, body)
-           ),
-           |(cmd, headers, body)| {
-               Frame {
-                   command: cmd,
-                   headers: HeaderList { headers },
-                   body: body.into()
-               }
-           }
-       )
-);
-/*
-*/
 
-fn parse_transmission(i: &[u8]) -> IResult<&[u8], Transmission> {
-    alt((
-        map(many1(line_ending), |_| Transmission::HeartBeat),
-        map(parse_frame, Transmission::CompleteFrame),
-    ))(i)
+impl ::std::error::Error for LimitExceeded {}
+
+// Where the decoder is within the frame at the front of the buffer. Bytes
+// belonging to completed states have already been removed from the buffer.
+enum DecodeState {
+    Command,
+    Headers {
+        command: Command,
+        headers: HeaderList,
+    },
+    Body {
+        command: Command,
+        headers: HeaderList,
+        content_length: Option<usize>,
+    },
+    StreamingBody {
+        remaining: usize,
+    },
 }
 
-/*
-named!(parse_transmission(&[u8]) -> Transmission,
-       alt!(
-           map!(many1!(line_ending), |_| Transmission::HeartBeat) |
-           map!(parse_frame, |f| Transmission::CompleteFrame(f))
-       )
-);
-*/
-pub struct Codec;
+/// A resumable decoder for server transmissions. Parse progress is kept
+/// between calls to `decode`, so each byte of a frame is only examined once
+/// no matter how many reads it takes to arrive.
+pub struct Codec {
+    limits: FrameLimits,
+    stream_threshold: Option<usize>,
+    decode_mode: DecodeMode,
+    sent_frames: bool,
+    state: DecodeState,
+    // Bytes of the current frame already consumed from the buffer
+    frame_offset: usize,
+    // Bytes at the front of the buffer known not to contain the delimiter
+    // being searched for
+    scanned: usize,
+    // Bytes left in the buffer by the last call to `decode`
+    unread: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec::with_limits(FrameLimits::default())
+    }
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec::default()
+    }
+
+    pub fn with_limits(limits: FrameLimits) -> Codec {
+        Codec {
+            limits,
+            stream_threshold: None,
+            decode_mode: DecodeMode::default(),
+            sent_frames: false,
+            state: DecodeState::Command,
+            frame_offset: 0,
+            scanned: 0,
+            unread: 0,
+        }
+    }
+
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+
+    /// Decodes MESSAGE frames whose `content-length` is at least `threshold`
+    /// as a `FrameHead` followed by `BodyChunk`s and a `BodyEnd`, instead of
+    /// buffering the whole body. Streamed bodies are not subject to
+    /// `max_frame_size`.
+    pub fn with_stream_threshold(mut self, threshold: Option<usize>) -> Codec {
+        self.stream_threshold = threshold;
+        self
+    }
+
+    /// How escape sequences in received headers are decoded. Headers of
+    /// CONNECTED frames are never decoded.
+    pub fn with_decode_mode(mut self, mode: DecodeMode) -> Codec {
+        self.decode_mode = mode;
+        self
+    }
+
+    // Whether bytes received were left undecoded by the last call to `decode`
+    pub(crate) fn has_unread(&self) -> bool {
+        self.unread > 0
+    }
+
+    // Decodes the frames written by the client rather than by a broker
+    pub(crate) fn for_sent_frames(mut self) -> Codec {
+        self.sent_frames = true;
+        self
+    }
+
+    fn should_stream(&self, command: Command, content_length: Option<usize>) -> bool {
+        match (command, self.stream_threshold, content_length) {
+            (Command::Message, Some(threshold), Some(length)) => length >= threshold,
+            _ => false,
+        }
+    }
+
+    fn check_frame_size(&self, frame_size: usize) -> Result<(), Error> {
+        if frame_size > self.limits.max_frame_size {
+            return Err(LimitExceeded::FrameSize(self.limits.max_frame_size).into());
+        }
+        Ok(())
+    }
+
+    // Removes the next line, including its EOL, from the front of `src`.
+    fn take_line(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        let max_header_length = self.limits.max_header_length;
+        let line_end = match src[self.scanned..].iter().position(|&b| b == b'\n') {
+            Some(offset) => self.scanned + offset,
+            None => {
+                self.scanned = src.len();
+                // Leave room for a '\r' which may precede the missing '\n'
+                if src.len() > max_header_length + 1 {
+                    return Err(LimitExceeded::HeaderLength(max_header_length).into());
+                }
+                self.check_frame_size(self.frame_offset + src.len())?;
+                return Ok(None);
+            }
+        };
+        let line = src.split_to(line_end + 1);
+        self.scanned = 0;
+        let eol_length = if line.ends_with(b"\r\n") { 2 } else { 1 };
+        if line.len() - eol_length > max_header_length {
+            return Err(LimitExceeded::HeaderLength(max_header_length).into());
+        }
+        self.frame_offset += line.len();
+        self.check_frame_size(self.frame_offset)?;
+        Ok(Some(line))
+    }
+
+    // Removes the body and its terminating NUL from the front of `src`.
+    fn take_body(
+        &mut self,
+        src: &mut BytesMut,
+        content_length: Option<usize>,
+    ) -> Result<Option<BytesMut>, Error> {
+        let body_length = match content_length {
+            Some(length) => {
+                if src.len() <= length {
+                    // Make sure the rest of the body can arrive without
+                    // the buffer being reallocated repeatedly
+                    src.reserve(length + 1 - src.len());
+                    return Ok(None);
+                }
+                if src[length] != 0 {
+                    return Err(Error::Protocol {
+                        offset: self.frame_offset + length,
+                        message: "expected NUL after content-length bytes".to_owned(),
+                    });
+                }
+                length
+            }
+            None => match src[self.scanned..].iter().position(|&b| b == 0) {
+                Some(offset) => self.scanned + offset,
+                None => {
+                    self.scanned = src.len();
+                    self.check_frame_size(self.frame_offset + src.len())?;
+                    return Ok(None);
+                }
+            },
+        };
+        self.check_frame_size(self.frame_offset + body_length + 1)?;
+        let body = src.split_to(body_length);
+        src.split_to(1);
+        self.frame_offset = 0;
+        self.scanned = 0;
+        Ok(Some(body))
+    }
+
+    fn protocol_error(
+        &self,
+        line: &[u8],
+        error: nom::Err<(&[u8], nom::error::ErrorKind)>,
+    ) -> Error {
+        let (offset, message) = match error {
+            nom::Err::Error((rest, kind)) | nom::Err::Failure((rest, kind)) => {
+                (line.len() - rest.len(), kind.description().to_owned())
+            }
+            nom::Err::Incomplete(_) => (line.len(), "unexpected end of line".to_owned()),
+        };
+        // `frame_offset` already includes the line
+        let offset = self.frame_offset - line.len() + offset;
+        warn!("parse error at byte {}: {}", offset, message);
+        Error::Protocol { offset, message }
+    }
+
+    fn decode_header<'l>(&self, command: Command, line: &'l [u8]) -> Result<Header, Error> {
+        let (key, value) = match parse_header(line) {
+            Ok((_, header)) => header,
+            Err(e) => return Err(self.protocol_error(line, e)),
+        };
+        // `frame_offset` already includes the line
+        let line_offset = self.frame_offset - line.len();
+        let decode_field = |field: &'l [u8], start: usize| -> Result<Cow<'l, str>, Error> {
+            let field = from_utf8(field).map_err(|e| Error::Protocol {
+                offset: line_offset + start + e.valid_up_to(),
+                message: "header is not valid UTF-8".to_owned(),
+            })?;
+            if !command.escapes_headers() {
+                return Ok(Cow::Borrowed(field));
+            }
+            match escape::decode(field, self.decode_mode) {
+                Ok(field) => Ok(field),
+                Err(e) => Err(Error::Protocol {
+                    offset: line_offset + start + e.position,
+                    message: e.to_string(),
+                }),
+            }
+        };
+        let key_length = key.len();
+        Ok(Header::new_raw(
+            decode_field(key, 0)?,
+            decode_field(value, key_length + 1)?,
+        ))
+    }
+
+    fn decode_step(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, Error> {
+        loop {
+            match mem::replace(&mut self.state, DecodeState::Command) {
+                DecodeState::Command => {
+                    let heart_beats = src
+                        .iter()
+                        .position(|&b| b != b'\n' && b != b'\r')
+                        .unwrap_or_else(|| src.len());
+                    if heart_beats > 0 {
+                        let eols = if src[..heart_beats].ends_with(b"\r") {
+                            // Wait for the '\n' completing the last EOL
+                            heart_beats - 1
+                        } else {
+                            heart_beats
+                        };
+                        if eols == 0 {
+                            return Ok(None);
+                        }
+                        src.split_to(eols);
+                        return Ok(Some(Transmission::HeartBeat));
+                    }
+                    let line = match self.take_line(src)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let command = match parse_command_line(&line, self.sent_frames) {
+                        Ok((_, command)) => command,
+                        Err(e) => return Err(self.protocol_error(&line, e)),
+                    };
+                    let headers = if command.escapes_headers() {
+                        HeaderList::decoded()
+                    } else {
+                        HeaderList::new()
+                    };
+                    self.state = DecodeState::Headers { command, headers };
+                }
+                DecodeState::Headers {
+                    command,
+                    mut headers,
+                } => {
+                    let line = match self.take_line(src)? {
+                        Some(line) => line,
+                        None => {
+                            self.state = DecodeState::Headers { command, headers };
+                            return Ok(None);
+                        }
+                    };
+                    if &line[..] == b"\n" || &line[..] == b"\r\n" {
+                        let content_length = headers
+                            .get_content_length()
+                            .map(|ContentLength(length)| length as usize);
+                        if self.should_stream(command, content_length) {
+                            self.state = DecodeState::StreamingBody {
+                                remaining: content_length.unwrap_or(0),
+                            };
+                            return Ok(Some(Transmission::FrameHead(Frame {
+                                command,
+                                headers,
+                                body: Vec::new(),
+                            })));
+                        }
+                        if let Some(length) = content_length {
+                            trace!("using content-length header: {}", length);
+                            self.check_frame_size(self.frame_offset.saturating_add(length) + 1)?;
+                        }
+                        self.state = DecodeState::Body {
+                            command,
+                            headers,
+                            content_length,
+                        };
+                        continue;
+                    }
+                    headers.push(self.decode_header(command, &line)?);
+                    if headers.headers.len() > self.limits.max_headers {
+                        return Err(LimitExceeded::HeaderCount(self.limits.max_headers).into());
+                    }
+                    self.state = DecodeState::Headers { command, headers };
+                }
+                DecodeState::Body {
+                    command,
+                    headers,
+                    content_length,
+                } => {
+                    return match self.take_body(src, content_length)? {
+                        Some(body) => Ok(Some(Transmission::CompleteFrame(Frame {
+                            command,
+                            headers,
+                            body: body.to_vec(),
+                        }))),
+                        None => {
+                            self.state = DecodeState::Body {
+                                command,
+                                headers,
+                                content_length,
+                            };
+                            Ok(None)
+                        }
+                    };
+                }
+                DecodeState::StreamingBody { remaining } => {
+                    if src.is_empty() {
+                        self.state = DecodeState::StreamingBody { remaining };
+                        return Ok(None);
+                    }
+                    if remaining > 0 {
+                        let chunk = src.split_to(remaining.min(src.len()));
+                        self.frame_offset += chunk.len();
+                        self.state = DecodeState::StreamingBody {
+                            remaining: remaining - chunk.len(),
+                        };
+                        return Ok(Some(Transmission::BodyChunk(chunk.to_vec())));
+                    }
+                    if src[0] != 0 {
+                        return Err(Error::Protocol {
+                            offset: self.frame_offset,
+                            message: "expected NUL after content-length bytes".to_owned(),
+                        });
+                    }
+                    src.split_to(1);
+                    self.frame_offset = 0;
+                    return Ok(Some(Transmission::BodyEnd));
+                }
+            }
+        }
+    }
+}
 
 impl Encoder for Codec {
     type Item = Transmission;
-    type Error = ::std::io::Error;
-    fn encode(
-        &mut self,
-        item: Transmission,
-        buffer: &mut BytesMut,
-    ) -> Result<(), ::std::io::Error> {
+    type Error = Error;
+    fn encode(&mut self, item: Transmission, buffer: &mut BytesMut) -> Result<(), Error> {
         item.write(buffer);
         Ok(())
     }
 }
 impl Decoder for Codec {
     type Item = Transmission;
-    type Error = ::std::io::Error;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, ::std::io::Error> {
-        use std::io::{Error, ErrorKind};
-
-        trace!("decoding data: {:?}", src);
-        let (point, data) = match parse_transmission(src) {
-            Ok((rest, data)) => (rest.len(), data),
-            Err(nom::Err::Incomplete(_)) => return Ok(None),
-            Err(e) => {
-                warn!("parse error: {:?}", e);
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("parse error: {:?}", e),
-                ));
-            }
-        };
-        let len = src.len().saturating_sub(point);
-        src.split_to(len);
-        Ok(Some(data))
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, Error> {
+        trace!("decoding {} bytes", src.len());
+        let result = self.decode_step(src);
+        if let Err(ref e) = result {
+            warn!("failed to decode frame: {}", e);
+            self.state = DecodeState::Command;
+            self.frame_offset = 0;
+            self.scanned = 0;
+        }
+        self.unread = src.len();
+        result
+    }
+}
+
+#[test]
+fn decode_within_limits() {
+    let mut codec = Codec::new();
+    let mut buffer =
+        BytesMut::from(&b"MESSAGE\ndestination:/queue/a\ncontent-length:5\n\nhello\0"[..]);
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => assert_eq!(frame.body, b"hello"),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_in_small_chunks() {
+    let body = vec![b'x'; 10_000];
+    let mut bytes = b"MESSAGE\r\nsubscription:stomp-rs/0\r\ncontent-length:10000\r\n\r\n".to_vec();
+    bytes.extend(&body);
+    bytes.extend(b"\0\n");
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::new();
+    let mut frames = vec![];
+    for chunk in bytes.chunks(7) {
+        buffer.extend_from_slice(chunk);
+        while let Some(transmission) = codec.decode(&mut buffer).unwrap() {
+            frames.push(transmission);
+        }
+    }
+    match frames.as_slice() {
+        [Transmission::CompleteFrame(frame), Transmission::HeartBeat] => {
+            assert_eq!(
+                frame
+                    .headers
+                    .get_header("subscription")
+                    .unwrap()
+                    .get_value(),
+                "stomp-rs/0"
+            );
+            assert_eq!(frame.body, body);
+        }
+        other => panic!("unexpected transmissions: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_streamed_body() {
+    let mut codec = Codec::new().with_stream_threshold(Some(4));
+    let mut buffer = BytesMut::from(&b"MESSAGE\ncontent-length:6\n\nabc"[..]);
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::FrameHead(frame))) => assert!(frame.body.is_empty()),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::BodyChunk(chunk))) => assert_eq!(chunk, b"abc"),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    buffer.extend_from_slice(b"def\0");
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::BodyChunk(chunk))) => assert_eq!(chunk, b"def"),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::BodyEnd)) => assert!(buffer.is_empty()),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_heart_beat() {
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::from(&b"\n"[..]);
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::HeartBeat)) => assert!(buffer.is_empty()),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_reports_parse_error_offset() {
+    let mut codec = Codec::new();
+    let mut buffer = BytesMut::from(&b"MESSAGE\nno-colon\n\n\0"[..]);
+    match codec.decode(&mut buffer) {
+        Err(Error::Protocol { offset, .. }) => assert_eq!(offset, 16),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_escaped_headers() {
+    let data = b"MESSAGE\nkey\\cname:a\\nb\\\\c\n\n\0CONNECTED\nserver:a\\nb\n\n\0";
+    let mut buffer = BytesMut::from(&data[..]);
+    let mut codec = Codec::new();
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => {
+            let header = frame.headers.get_header("key:name").unwrap();
+            assert_eq!(header.get_value(), "a\nb\\c");
+            assert_eq!(
+                frame.headers.escaped(header).get_raw(),
+                r"key\cname:a\nb\\c"
+            );
+        }
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+    match codec.decode(&mut buffer) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => {
+            assert_eq!(frame.headers.get_server().map(|s| s.0), Some(r"a\nb"));
+        }
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_undefined_escape() {
+    let data = &b"MESSAGE\nkey:tab\\there\n\n\0"[..];
+    match Codec::new().decode(&mut BytesMut::from(data)) {
+        Err(Error::Protocol { offset, .. }) => assert_eq!(offset, 15),
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+    let mut codec = Codec::new().with_decode_mode(DecodeMode::Lenient);
+    match codec.decode(&mut BytesMut::from(data)) {
+        Ok(Some(Transmission::CompleteFrame(frame))) => {
+            assert_eq!(
+                frame.headers.get_header("key").unwrap().get_value(),
+                r"tab\there"
+            );
+        }
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_rejects_large_content_length() {
+    let mut codec = Codec::with_limits(FrameLimits {
+        max_frame_size: 1024,
+        ..FrameLimits::default()
+    });
+    let mut buffer = BytesMut::from(&b"MESSAGE\ncontent-length:1000000\n\nhello"[..]);
+    match codec.decode(&mut buffer) {
+        Err(Error::LimitExceeded(LimitExceeded::FrameSize(1024))) => {}
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_rejects_unterminated_body() {
+    let mut codec = Codec::with_limits(FrameLimits {
+        max_frame_size: 16,
+        ..FrameLimits::default()
+    });
+    let mut buffer = BytesMut::from(&b"MESSAGE\n\n0123456789abcdef"[..]);
+    assert!(codec.decode(&mut buffer).is_err());
+}
+
+#[test]
+fn decode_rejects_too_many_headers() {
+    let mut codec = Codec::with_limits(FrameLimits {
+        max_headers: 2,
+        ..FrameLimits::default()
+    });
+    let mut buffer = BytesMut::from(&b"MESSAGE\na:1\nb:2\nc:3\n"[..]);
+    match codec.decode(&mut buffer) {
+        Err(Error::LimitExceeded(LimitExceeded::HeaderCount(2))) => {}
+        other => panic!("unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn decode_rejects_long_header_line() {
+    let mut codec = Codec::with_limits(FrameLimits {
+        max_header_length: 8,
+        ..FrameLimits::default()
+    });
+    let mut buffer = BytesMut::from(&b"MESSAGE\nsome-long-header"[..]);
+    match codec.decode(&mut buffer) {
+        Err(Error::LimitExceeded(LimitExceeded::HeaderLength(8))) => {}
+        other => panic!("unexpected decode result: {:?}", other),
     }
 }
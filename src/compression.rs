@@ -0,0 +1,201 @@
+//! Compression of message bodies. Compressed messages carry a
+//! `content-encoding` header naming the algorithm, and are decompressed by
+//! the receiving `Session` before being delivered. Gzip requires the `gzip`
+//! feature and zstd the `zstd` feature.
+use crate::frame::Frame;
+use crate::header::Header;
+use std::io::{self, Read};
+
+// Bodies smaller than this are not worth compressing by default
+const DEFAULT_MIN_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// The value of the `content-encoding` header for this algorithm.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_encoding(encoding: &str) -> Option<Compression> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            #[cfg(feature = "gzip")]
+            "gzip" | "x-gzip" => Some(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use flate2::write::GzEncoder;
+                use std::io::Write;
+
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+
+    /// Decompresses `body`, failing if the result would be longer than
+    /// `limit` bytes.
+    pub fn decompress(self, body: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(body)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(zstd::Decoder::new(body)?),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompressed body exceeds {} bytes", limit),
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Compresses the body of a message with `algorithm` if it is at least
+/// `min_size` bytes long. Bodies streamed with `Session::message_stream` are
+/// sent uncompressed.
+#[derive(Clone, Copy, Debug)]
+pub struct Compress {
+    pub algorithm: Compression,
+    pub min_size: usize,
+}
+
+impl Compress {
+    pub fn new(algorithm: Compression) -> Compress {
+        Compress {
+            algorithm,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Compress {
+        self.min_size = min_size;
+        self
+    }
+
+    pub(crate) fn apply(self, frame: &mut Frame) {
+        if frame.body.len() < self.min_size {
+            return;
+        }
+        match self.algorithm.compress(&frame.body) {
+            Ok(compressed) => {
+                let content_length = compressed.len().to_string();
+                frame.body = compressed;
+                frame
+                    .headers
+                    .insert(Header::new("content-encoding", self.algorithm.encoding()));
+                frame
+                    .headers
+                    .insert(Header::new("content-length", &content_length));
+            }
+            Err(e) => warn!("Sending message uncompressed, compression failed: {}", e),
+        }
+    }
+}
+
+// Replaces the body of a received frame bearing a known `content-encoding`
+// with its decompressed form.
+pub(crate) fn decompress_frame(frame: &mut Frame, limit: usize) -> io::Result<()> {
+    let compression = match frame
+        .headers
+        .get_header("content-encoding")
+        .and_then(|header| Compression::from_encoding(header.get_value()))
+    {
+        Some(compression) => compression,
+        None => return Ok(()),
+    };
+    let body = compression.decompress(&frame.body, limit)?;
+    frame.headers.remove("content-encoding");
+    frame
+        .headers
+        .insert(Header::new("content-length", &body.len().to_string()));
+    frame.body = body;
+    Ok(())
+}
+
+#[test]
+fn compress_large_bodies_only() {
+    let algorithms = vec![
+        #[cfg(feature = "gzip")]
+        Compression::Gzip,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+    ];
+    for algorithm in algorithms {
+        let body = br#"{"items":["tea","tea","tea","tea","tea","tea","tea"]}"#.repeat(64);
+        let mut frame = Frame::send("/queue/a", &body);
+        Compress::new(algorithm).apply(&mut frame);
+        assert!(frame.body.len() < body.len());
+        assert_eq!(
+            frame
+                .headers
+                .get_header("content-encoding")
+                .unwrap()
+                .get_value(),
+            algorithm.encoding()
+        );
+        assert!(decompress_frame(&mut frame.clone(), body.len() - 1).is_err());
+        decompress_frame(&mut frame, body.len()).unwrap();
+        assert_eq!(frame.body, body);
+        assert!(!frame.headers.contains("content-encoding"));
+        assert_eq!(
+            frame.headers.get_content_length().map(|length| length.0),
+            Some(body.len() as u32)
+        );
+
+        let mut small = Frame::send("/queue/a", b"tea");
+        Compress::new(algorithm).apply(&mut small);
+        assert_eq!(small.body, b"tea");
+        assert!(!small.headers.contains("content-encoding"));
+    }
+}
+
+#[test]
+fn send_streamed_bodies_uncompressed() {
+    use crate::test_broker::{broker, connected_session, header_value, run_until, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let send = client.read();
+        assert!(!send.headers.contains("content-encoding"));
+        assert_eq!(header_value(&send, "content-length"), "3");
+        assert_eq!(send.body, b"tea");
+    });
+    let (mut core, mut session) = connected_session(port);
+    #[cfg(feature = "gzip")]
+    let algorithm = Compression::Gzip;
+    #[cfg(not(feature = "gzip"))]
+    let algorithm = Compression::Zstd;
+    session
+        .message_stream("/queue/a", 3, &b"tea"[..])
+        .with(Compress::new(algorithm).min_size(0))
+        .send();
+    run_until(&mut core, &mut session, |_, _| broker.is_finished());
+    broker.join().unwrap();
+}
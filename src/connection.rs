@@ -31,18 +31,16 @@ impl Connection {
         server_tx_ms: u32,
         server_rx_ms: u32,
     ) -> (u32, u32) {
-        let heartbeat_tx_ms: u32;
-        let heartbeat_rx_ms: u32;
-        if client_tx_ms == 0 || server_rx_ms == 0 {
-            heartbeat_tx_ms = 0;
+        let heartbeat_tx_ms = if client_tx_ms == 0 || server_rx_ms == 0 {
+            0
         } else {
-            heartbeat_tx_ms = max(client_tx_ms, server_rx_ms);
-        }
-        if client_rx_ms == 0 || server_tx_ms == 0 {
-            heartbeat_rx_ms = 0;
+            max(client_tx_ms, server_rx_ms)
+        };
+        let heartbeat_rx_ms = if client_rx_ms == 0 || server_tx_ms == 0 {
+            0
         } else {
-            heartbeat_rx_ms = max(client_rx_ms, server_tx_ms);
-        }
+            max(client_rx_ms, server_tx_ms)
+        };
         (heartbeat_tx_ms, heartbeat_rx_ms)
     }
 }
@@ -0,0 +1,284 @@
+//! Concurrent handling of the messages of a subscription. A subscription
+//! started with `SubscriptionBuilder::consume` runs a handler for each of its
+//! messages, at most `concurrency` at a time, and acknowledges each message
+//! once its handler succeeds. Messages whose handler fails are passed to
+//! `Session::message_failed`, so they are NACKed or dead-lettered according
+//! to the subscription's `RedeliveryPolicy`. While `concurrency` messages
+//! are waiting for a handler, the session reads no more frames.
+use crate::error::Error;
+use crate::frame::Frame;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use std::collections::VecDeque;
+
+/// The work of handling a message, failing with the reason it could not be.
+pub type HandlerFuture = Box<dyn Future<Item = (), Error = String>>;
+
+/// Starts handling a message.
+pub type MessageHandler = Box<dyn FnMut(&Frame) -> HandlerFuture>;
+
+// A message being handled
+struct InFlight {
+    frame: Frame,
+    work: HandlerFuture,
+    // False once the connection the message arrived on is lost
+    acknowledge: bool,
+}
+
+// How the handling of a message ended
+pub(crate) enum Outcome {
+    Handled(Frame),
+    Failed(Frame, String),
+}
+
+pub(crate) struct Consumer {
+    concurrency: usize,
+    handler: MessageHandler,
+    backlog: VecDeque<Frame>,
+    in_flight: Vec<InFlight>,
+    stopping: Vec<oneshot::Sender<()>>,
+}
+
+impl Consumer {
+    pub fn new(concurrency: usize, handler: MessageHandler) -> Consumer {
+        Consumer {
+            concurrency: concurrency.max(1),
+            handler,
+            backlog: VecDeque::new(),
+            in_flight: Vec::new(),
+            stopping: Vec::new(),
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        !self.stopping.is_empty()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.backlog.is_empty() && self.in_flight.is_empty()
+    }
+
+    // Whether as many messages are waiting as can be handled at once, so that
+    // no more should be read for now
+    pub fn is_full(&self) -> bool {
+        self.backlog.len() >= self.concurrency
+    }
+
+    // Queues a message until a handler is free
+    pub fn push(&mut self, frame: Frame) {
+        self.backlog.push_back(frame);
+    }
+
+    // Stops taking new messages, resolving the future once the messages
+    // already received have been handled
+    pub fn stop(&mut self) -> ConsumerStopped {
+        let (sender, receiver) = oneshot::channel();
+        self.stopping.push(sender);
+        ConsumerStopped {
+            receiver: Some(receiver),
+        }
+    }
+
+    pub fn stopped(self) {
+        for sender in self.stopping {
+            let _ = sender.send(());
+        }
+    }
+
+    // Forgets the messages received on a lost connection. The broker
+    // redelivers them, so those being handled are not acknowledged.
+    pub fn disconnected(&mut self) {
+        self.backlog.clear();
+        for in_flight in self.in_flight.iter_mut() {
+            in_flight.acknowledge = false;
+        }
+    }
+
+    // Starts handlers while fewer than `concurrency` are running, returning
+    // the outcomes of those which have finished
+    pub fn poll(&mut self) -> Vec<Outcome> {
+        let mut outcomes = Vec::new();
+        loop {
+            while self.in_flight.len() < self.concurrency {
+                let frame = match self.backlog.pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let work = (self.handler)(&frame);
+                self.in_flight.push(InFlight {
+                    frame,
+                    work,
+                    acknowledge: true,
+                });
+            }
+            let running = self.in_flight.len();
+            self.in_flight.retain_mut(|in_flight| {
+                let outcome = match in_flight.work.poll() {
+                    Ok(Async::NotReady) => return true,
+                    Ok(Async::Ready(())) => Outcome::Handled(in_flight.frame.clone()),
+                    Err(reason) => Outcome::Failed(in_flight.frame.clone(), reason),
+                };
+                if in_flight.acknowledge {
+                    outcomes.push(outcome);
+                }
+                false
+            });
+            if self.in_flight.len() == running || self.backlog.is_empty() {
+                return outcomes;
+            }
+        }
+    }
+}
+
+/// Resolves once a consumer stopped with `Session::stop_consumer` has
+/// handled the messages it had received, and its subscription has ended.
+/// The `Session` must be polled meanwhile.
+pub struct ConsumerStopped {
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl ConsumerStopped {
+    // For a subscription without a consumer
+    pub(crate) fn immediately() -> ConsumerStopped {
+        ConsumerStopped { receiver: None }
+    }
+}
+
+impl Future for ConsumerStopped {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.receiver {
+            Some(ref mut receiver) => receiver.poll().map_err(|_| Error::Disconnected),
+            None => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[test]
+fn limit_concurrent_handlers() {
+    use futures::future;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Each handler waits for its message's result to be sent
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let handler_results = results.clone();
+    let handler: MessageHandler = Box::new(move |_: &Frame| {
+        let (sender, receiver) = oneshot::channel::<std::result::Result<(), String>>();
+        handler_results.borrow_mut().push(Some(sender));
+        Box::new(receiver.then(|result| result.unwrap()))
+    });
+    let mut consumer = Consumer::new(2, handler);
+    for body in &["a", "b", "c"] {
+        consumer.push(Frame::send("/queue/a", body.as_bytes()));
+    }
+
+    // Enough messages are waiting for every handler
+    assert!(consumer.is_full());
+
+    future::lazy(move || {
+        assert!(consumer.poll().is_empty());
+        assert_eq!(results.borrow().len(), 2);
+        assert!(!consumer.is_full());
+
+        let finish = |i: usize, result| {
+            let sender = results.borrow_mut()[i].take().unwrap();
+            sender.send(result).unwrap();
+        };
+        finish(1, Err("invalid".to_owned()));
+        match consumer.poll().as_slice() {
+            [Outcome::Failed(frame, reason)] => {
+                assert_eq!(frame.body, b"b");
+                assert_eq!(reason, "invalid");
+            }
+            _ => panic!("expected the second message to fail"),
+        }
+        assert_eq!(results.borrow().len(), 3);
+
+        finish(0, Ok(()));
+        consumer.disconnected();
+        assert!(consumer.poll().is_empty());
+        assert!(!consumer.is_idle());
+        finish(2, Ok(()));
+        assert!(consumer.poll().is_empty());
+        assert!(consumer.is_idle());
+        Ok::<(), ()>(())
+    })
+    .wait()
+    .unwrap();
+}
+
+#[test]
+fn acknowledge_handled_messages_and_drain_on_stop() {
+    use crate::dialect::activemq::PrefetchCount;
+    use crate::subscription::AckMode;
+    use crate::test_broker::{broker, connected_session, header_value, run_until, wait, Client};
+    use futures::future;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    let (stopping, stopped_signal) = mpsc::channel();
+    let (port, broker) = broker(move |listener| {
+        let mut client = Client::connect(&listener);
+        let subscribe = client.read();
+        assert_eq!(header_value(&subscribe, "ack"), "client-individual");
+        assert_eq!(header_value(&subscribe, "activemq.prefetchSize"), "2");
+        assert!(!subscribe.headers.contains("prefetch-count"));
+        let sub_id = header_value(&subscribe, "id").to_owned();
+        let mut settled = |message_id: &str, body: &str, command: &str| {
+            client.message(&sub_id, message_id, body);
+            let frame = client.read();
+            assert_eq!(frame.command.as_str(), command);
+            assert_eq!(header_value(&frame, "id"), message_id);
+        };
+        settled("1", "ok", "ACK");
+        settled("2", "fail", "NACK");
+
+        client.message(&sub_id, "3", "slow");
+        stopped_signal.recv().unwrap();
+        // Arrives while the consumer is stopping, so is neither handled nor
+        // acknowledged
+        client.message(&sub_id, "4", "ok");
+        let ack = client.read();
+        assert_eq!(ack.command.as_str(), "ACK");
+        assert_eq!(header_value(&ack, "id"), "3");
+        let unsubscribe = client.read();
+        assert_eq!(unsubscribe.command.as_str(), "UNSUBSCRIBE");
+        assert_eq!(header_value(&unsubscribe, "id"), sub_id);
+        client.quiet(Duration::from_millis(100));
+    });
+    let (mut core, mut session) = connected_session(port);
+
+    let slow = Rc::new(RefCell::new(None));
+    let handler_slow = slow.clone();
+    let id = session
+        .subscription("/queue/a")
+        .with(AckMode::Client)
+        .with(PrefetchCount(2))
+        .consume(2, move |frame: &Frame| -> HandlerFuture {
+            match &frame.body[..] {
+                b"fail" => Box::new(future::err("invalid".to_owned())),
+                b"slow" => {
+                    let (sender, receiver) = oneshot::channel();
+                    *handler_slow.borrow_mut() = Some(sender);
+                    Box::new(receiver.map_err(|_| "canceled".to_owned()))
+                }
+                _ => Box::new(future::ok(())),
+            }
+        });
+    run_until(&mut core, &mut session, |_, _| slow.borrow().is_some());
+
+    let stopped = session.stop_consumer(&id);
+    stopping.send(()).unwrap();
+    let start = Instant::now();
+    run_until(&mut core, &mut session, |_, _| {
+        start.elapsed() > Duration::from_millis(100)
+    });
+    slow.borrow_mut().take().unwrap().send(()).unwrap();
+    wait(&mut core, &mut session, stopped).unwrap();
+    broker.join().unwrap();
+}
@@ -0,0 +1,215 @@
+//! Deduplication of messages delivered more than once, e.g. redelivered
+//! after a reconnection although they had already been handled. A
+//! subscription with `Deduplicate` remembers the ids of the recent messages
+//! it has acknowledged, and drops those it has already seen. Messages which
+//! were NACKed, or not acknowledged before the connection was lost, are
+//! handled again when redelivered.
+use crate::header::HeaderList;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Drops messages whose id is among those of the last `capacity` messages
+/// acknowledged on the subscription, within `window` if one is set. Messages
+/// of `AckMode::Auto` subscriptions count as acknowledged once received, and
+/// with `AckMode::Client` only the messages passed to
+/// `Session::acknowledge_frame` count, not those acknowledged along with
+/// them. Messages are identified by their `message-id` header unless another
+/// header is chosen with `by_header`. Messages without that header are never
+/// dropped. Dropped messages are acknowledged with
+/// `AckMode::ClientIndividual`, and `Session::duplicates` counts them. With
+/// `AckMode::Client` they are left to be covered by the next message the
+/// application acknowledges.
+#[derive(Clone, Debug)]
+pub struct Deduplicate {
+    pub header: String,
+    pub capacity: usize,
+    pub window: Option<Duration>,
+}
+
+impl Default for Deduplicate {
+    fn default() -> Deduplicate {
+        Deduplicate {
+            header: "message-id".to_owned(),
+            capacity: 10_000,
+            window: None,
+        }
+    }
+}
+
+impl Deduplicate {
+    pub fn new() -> Deduplicate {
+        Deduplicate::default()
+    }
+
+    /// Identifies messages by this header, e.g. an id set by the producer.
+    pub fn by_header(mut self, header: &str) -> Deduplicate {
+        self.header = header.to_owned();
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Deduplicate {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Deduplicate {
+        self.window = Some(window);
+        self
+    }
+}
+
+// The ids seen by a subscription, oldest first
+pub(crate) struct SeenMessages {
+    config: Deduplicate,
+    seen: HashMap<String, Instant>,
+    order: VecDeque<(String, Instant)>,
+    duplicates: u64,
+}
+
+impl SeenMessages {
+    pub fn new(config: Deduplicate) -> SeenMessages {
+        SeenMessages {
+            config,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            duplicates: 0,
+        }
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    // Whether the message has already been seen
+    pub fn is_duplicate(&mut self, headers: &HeaderList, now: Instant) -> bool {
+        self.expire(now);
+        let duplicate = headers
+            .get_header(&self.config.header)
+            .is_some_and(|header| self.seen.contains_key(header.get_value()));
+        if duplicate {
+            self.duplicates += 1;
+        }
+        duplicate
+    }
+
+    // Records a message which has been handled, so that its redeliveries are
+    // dropped
+    pub fn record(&mut self, headers: &HeaderList, now: Instant) {
+        let id = match headers.get_header(&self.config.header) {
+            Some(header) => header.get_value(),
+            None => return,
+        };
+        if self.seen.contains_key(id) {
+            return;
+        }
+        self.seen.insert(id.to_owned(), now);
+        self.order.push_back((id.to_owned(), now));
+        while self.order.len() > self.config.capacity {
+            self.pop_oldest();
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let window = match self.config.window {
+            Some(window) => window,
+            None => return,
+        };
+        while let Some((_, seen_at)) = self.order.front() {
+            if now.duration_since(*seen_at) < window {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((id, _)) = self.order.pop_front() {
+            self.seen.remove(&id);
+        }
+    }
+}
+
+#[test]
+fn drop_duplicate_messages() {
+    use crate::header::Header;
+
+    let now = Instant::now();
+    let message = |id| header_list!["message-id" => id];
+    let mut seen = SeenMessages::new(Deduplicate::new().capacity(2));
+    assert!(!seen.is_duplicate(&message("m-1"), now));
+    // Only messages which have been handled are recorded
+    assert!(!seen.is_duplicate(&message("m-1"), now));
+    seen.record(&message("m-1"), now);
+    seen.record(&message("m-2"), now);
+    assert!(seen.is_duplicate(&message("m-1"), now));
+    seen.record(&header_list!["destination" => "/queue/a"], now);
+    assert!(!seen.is_duplicate(&header_list!["destination" => "/queue/a"], now));
+
+    // The oldest id is forgotten beyond the capacity
+    seen.record(&message("m-3"), now);
+    assert!(!seen.is_duplicate(&message("m-1"), now));
+    assert!(seen.is_duplicate(&message("m-3"), now));
+    assert_eq!(seen.duplicates(), 2);
+
+    let window = Duration::from_secs(60);
+    let mut seen = SeenMessages::new(Deduplicate::new().by_header("x-event-id").window(window));
+    let event = header_list!["message-id" => "m-1", "x-event-id" => "e-1"];
+    seen.record(&event, now);
+    assert!(seen.is_duplicate(&event, now + Duration::from_secs(59)));
+    assert!(!seen.is_duplicate(&event, now + window));
+}
+
+#[test]
+fn drop_and_acknowledge_redelivered_messages() {
+    use crate::session::SessionEvent;
+    use crate::subscription::{AckMode, AckOrNack};
+    use crate::test_broker::{broker, connected_session, header_value, run_until, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let subscribe = client.read();
+        let sub_id = header_value(&subscribe, "id").to_owned();
+        let mut settle = |message_id: &str, body: &str, command: &str| {
+            client.message(&sub_id, message_id, body);
+            let frame = client.read();
+            assert_eq!(frame.command.as_str(), command);
+            assert_eq!(header_value(&frame, "id"), message_id);
+        };
+        settle("1", "one", "ACK");
+        // Redelivered although acknowledged, so dropped
+        settle("1", "one", "ACK");
+        settle("2", "two", "NACK");
+        // Redelivered after a NACK, so handled again
+        settle("2", "two", "ACK");
+    });
+    let (mut core, mut session) = connected_session(port);
+    let id = session
+        .subscription("/queue/a")
+        .with(AckMode::ClientIndividual)
+        .with(Deduplicate::new())
+        .start();
+
+    let mut handled = Vec::new();
+    let mut seen = 0;
+    run_until(&mut core, &mut session, |session, events| {
+        for event in &events[seen..] {
+            if let SessionEvent::Message { frame, .. } = event {
+                let which = if frame.body == b"two" && !handled.contains(&frame.body) {
+                    AckOrNack::Nack
+                } else {
+                    AckOrNack::Ack
+                };
+                handled.push(frame.body.clone());
+                session.acknowledge_frame(frame, which);
+            }
+        }
+        seen = events.len();
+        broker.is_finished()
+    });
+    broker.join().unwrap();
+    assert_eq!(
+        handled,
+        vec![b"one".to_vec(), b"two".to_vec(), b"two".to_vec()]
+    );
+    assert_eq!(session.duplicates(&id), Some(1));
+}
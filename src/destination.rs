@@ -0,0 +1,212 @@
+//! Destination names. Most brokers distinguish queues from topics with a
+//! prefix such as `/queue/`; RabbitMQ adds exchanges and pre-declared
+//! queues, and names without a known prefix are kept as they are.
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+// Builds the destination with a name following its prefix
+type Variant = fn(String) -> Destination;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Destination {
+    /// `/queue/{name}`: each message is delivered to one consumer.
+    Queue(String),
+    /// `/topic/{name}`: each message is delivered to every subscription.
+    Topic(String),
+    /// `/temp-queue/{name}`: a queue which exists for the lifetime of the
+    /// connection.
+    TempQueue(String),
+    /// `/temp-topic/{name}`: a topic which exists for the lifetime of the
+    /// connection.
+    TempTopic(String),
+    /// `/exchange/{name}/{routing key}`: a RabbitMQ exchange.
+    Exchange {
+        name: String,
+        routing_key: Option<String>,
+    },
+    /// `/amq/queue/{name}`: a RabbitMQ queue declared outside STOMP.
+    AmqQueue(String),
+    /// Any other name, e.g. an Artemis address.
+    Other(String),
+}
+
+/// A destination which cannot be sent to a broker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidDestination {
+    pub destination: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidDestination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid destination '{}': {}",
+            self.destination, self.reason
+        )
+    }
+}
+
+impl error::Error for InvalidDestination {}
+
+impl Destination {
+    /// Checks that the destination can be sent: names must not be empty or
+    /// contain control characters, and exchange names must not contain `/`.
+    pub fn validate(&self) -> Result<(), InvalidDestination> {
+        let invalid = |reason| {
+            Err(InvalidDestination {
+                destination: self.to_string(),
+                reason,
+            })
+        };
+        let (name, routing_key) = match self {
+            Destination::Queue(name)
+            | Destination::Topic(name)
+            | Destination::TempQueue(name)
+            | Destination::TempTopic(name)
+            | Destination::AmqQueue(name)
+            | Destination::Other(name) => (name, None),
+            Destination::Exchange { name, routing_key } => {
+                if name.contains('/') {
+                    return invalid("exchange names cannot contain '/'");
+                }
+                (name, routing_key.as_ref())
+            }
+        };
+        if name.is_empty() || routing_key.is_some_and(|key| key.is_empty()) {
+            return invalid("empty name");
+        }
+        if name
+            .chars()
+            .chain(routing_key.into_iter().flat_map(|key| key.chars()))
+            .any(char::is_control)
+        {
+            return invalid("contains control characters");
+        }
+        Ok(())
+    }
+}
+
+// Classifies a destination by its prefix, without validating it
+impl From<&str> for Destination {
+    fn from(destination: &str) -> Destination {
+        let prefixes: [(&str, Variant); 5] = [
+            ("/queue/", Destination::Queue),
+            ("/topic/", Destination::Topic),
+            ("/temp-queue/", Destination::TempQueue),
+            ("/temp-topic/", Destination::TempTopic),
+            ("/amq/queue/", Destination::AmqQueue),
+        ];
+        for (prefix, variant) in prefixes.iter() {
+            if let Some(name) = destination.strip_prefix(prefix) {
+                return variant(name.to_owned());
+            }
+        }
+        if let Some(exchange) = destination.strip_prefix("/exchange/") {
+            let mut parts = exchange.splitn(2, '/');
+            return Destination::Exchange {
+                name: parts.next().unwrap_or_default().to_owned(),
+                routing_key: parts.next().map(str::to_owned),
+            };
+        }
+        Destination::Other(destination.to_owned())
+    }
+}
+
+impl From<&String> for Destination {
+    fn from(destination: &String) -> Destination {
+        Destination::from(destination.as_str())
+    }
+}
+
+impl From<String> for Destination {
+    fn from(destination: String) -> Destination {
+        Destination::from(destination.as_str())
+    }
+}
+
+impl From<&Destination> for Destination {
+    fn from(destination: &Destination) -> Destination {
+        destination.clone()
+    }
+}
+
+impl FromStr for Destination {
+    type Err = InvalidDestination;
+
+    fn from_str(value: &str) -> Result<Destination, InvalidDestination> {
+        let destination = Destination::from(value);
+        destination.validate()?;
+        Ok(destination)
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Queue(name) => write!(f, "/queue/{}", name),
+            Destination::Topic(name) => write!(f, "/topic/{}", name),
+            Destination::TempQueue(name) => write!(f, "/temp-queue/{}", name),
+            Destination::TempTopic(name) => write!(f, "/temp-topic/{}", name),
+            Destination::Exchange {
+                name,
+                routing_key: Some(routing_key),
+            } => write!(f, "/exchange/{}/{}", name, routing_key),
+            Destination::Exchange {
+                name,
+                routing_key: None,
+            } => write!(f, "/exchange/{}", name),
+            Destination::AmqQueue(name) => write!(f, "/amq/queue/{}", name),
+            Destination::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+#[test]
+fn parse_destinations() {
+    let parsed = |value: &str| value.parse::<Destination>();
+    assert_eq!(
+        parsed("/queue/orders"),
+        Ok(Destination::Queue("orders".to_owned()))
+    );
+    assert_eq!(
+        parsed("/temp-topic/t"),
+        Ok(Destination::TempTopic("t".to_owned()))
+    );
+    assert_eq!(
+        parsed("/exchange/amq.topic/orders.eu"),
+        Ok(Destination::Exchange {
+            name: "amq.topic".to_owned(),
+            routing_key: Some("orders.eu".to_owned()),
+        })
+    );
+    assert_eq!(
+        parsed("prices::eu"),
+        Ok(Destination::Other("prices::eu".to_owned()))
+    );
+    for destination in &[
+        "/queue/a/b",
+        "/topic/prices",
+        "/temp-queue/replies",
+        "/amq/queue/legacy",
+        "/exchange/events",
+        "/exchange/amq.direct/a/b",
+        "orders",
+    ] {
+        assert_eq!(&parsed(destination).unwrap().to_string(), destination);
+    }
+
+    assert!(parsed("/queue/").is_err());
+    assert!(parsed("").is_err());
+    assert!(parsed("/exchange/events/").is_err());
+    assert_eq!(
+        parsed("/topic/a\nb").map_err(|e| e.reason),
+        Err("contains control characters")
+    );
+    let exchange = Destination::Exchange {
+        name: "a/b".to_owned(),
+        routing_key: None,
+    };
+    assert!(exchange.validate().is_err());
+}
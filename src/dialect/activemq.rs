@@ -0,0 +1,96 @@
+//! ActiveMQ Classic. Destinations are prefixed with their type, e.g.
+//! `/queue/orders`.
+use crate::destination::Destination;
+use crate::header::TypedHeader;
+use std::fmt;
+
+pub use super::{Expires, Persistent, Priority, Selector};
+
+/// Makes a topic subscription durable under this name. The connection must
+/// also set a `ClientId`, and the pair identifies the subscription across
+/// connections. `Unsubscribe::Destroy` deletes it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DurableSubscriptionName<'a>(pub &'a str);
+
+/// The number of messages the broker dispatches to the subscription before
+/// waiting for acknowledgements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefetchCount(pub u32);
+
+pub fn queue(name: &str) -> Destination {
+    Destination::Queue(name.to_owned())
+}
+
+pub fn topic(name: &str) -> Destination {
+    Destination::Topic(name.to_owned())
+}
+
+/// A queue which exists for the lifetime of the connection.
+pub fn temp_queue(name: &str) -> Destination {
+    Destination::TempQueue(name.to_owned())
+}
+
+/// A topic which exists for the lifetime of the connection.
+pub fn temp_topic(name: &str) -> Destination {
+    Destination::TempTopic(name.to_owned())
+}
+
+impl<'a> TypedHeader<'a> for DurableSubscriptionName<'a> {
+    const NAME: &'static str = "activemq.subscriptionName";
+
+    fn parse(value: &'a str) -> Option<DurableSubscriptionName<'a>> {
+        Some(DurableSubscriptionName(value))
+    }
+}
+
+impl fmt::Display for DurableSubscriptionName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for PrefetchCount {
+    const NAME: &'static str = "activemq.prefetchSize";
+
+    fn parse(value: &'a str) -> Option<PrefetchCount> {
+        value.parse().ok().map(PrefetchCount)
+    }
+}
+
+impl fmt::Display for PrefetchCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[test]
+fn activemq_headers() {
+    use crate::header::HeaderList;
+
+    let mut headers = HeaderList::new();
+    headers.set_typed(Persistent(true));
+    headers.set_typed(Priority(7));
+    headers.set_typed(Expires(1_700_000_000_000));
+    headers.set_typed(Selector("region = 'EU'"));
+    headers.set_typed(DurableSubscriptionName("audit"));
+    headers.set_typed(PrefetchCount(100));
+    let raw: Vec<String> = headers.iter().map(|h| h.get_raw()).collect();
+    assert_eq!(
+        raw,
+        vec![
+            "persistent:true",
+            "priority:7",
+            "expires:1700000000000",
+            "selector:region = 'EU'",
+            "activemq.subscriptionName:audit",
+            "activemq.prefetchSize:100",
+        ]
+    );
+    assert_eq!(headers.typed::<PrefetchCount>(), Some(PrefetchCount(100)));
+    assert_eq!(headers.typed::<Persistent>(), Some(Persistent(true)));
+
+    assert_eq!(queue("orders").to_string(), "/queue/orders");
+    assert_eq!(topic("prices").to_string(), "/topic/prices");
+    assert_eq!(temp_queue("replies").to_string(), "/temp-queue/replies");
+    assert_eq!(temp_topic("events").to_string(), "/temp-topic/events");
+}
@@ -0,0 +1,113 @@
+//! ActiveMQ Artemis. Destinations are address names, routed to anycast
+//! (queue) or multicast (topic) queues according to `RoutingType` unless the
+//! acceptor configures `anycastPrefix` and `multicastPrefix`.
+use crate::destination::Destination;
+use crate::header::TypedHeader;
+use std::fmt;
+
+pub use super::{Expires, Persistent, Priority, Selector};
+
+/// Makes a multicast subscription durable under this name. The connection
+/// must also set a `ClientId`. `Unsubscribe::Destroy` deletes it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DurableSubscriptionName<'a>(pub &'a str);
+
+/// The number of bytes of messages the broker sends to the subscription
+/// before waiting for acknowledgements; Artemis' equivalent of a prefetch
+/// count. -1 means unbounded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsumerWindowSize(pub i32);
+
+/// How messages sent to an address reach its queues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoutingType {
+    /// Point-to-point: each message is delivered to one consumer.
+    Anycast,
+    /// Publish-subscribe: each message is delivered to every subscription.
+    Multicast,
+}
+
+pub fn address(name: &str) -> Destination {
+    Destination::Other(name.to_owned())
+}
+
+/// The fully qualified name of `queue` bound to `address`, used to consume
+/// from a particular queue of a multicast address.
+pub fn fully_qualified_queue(address: &str, queue: &str) -> Destination {
+    Destination::Other(format!("{}::{}", address, queue))
+}
+
+impl<'a> TypedHeader<'a> for DurableSubscriptionName<'a> {
+    const NAME: &'static str = "durable-subscription-name";
+
+    fn parse(value: &'a str) -> Option<DurableSubscriptionName<'a>> {
+        Some(DurableSubscriptionName(value))
+    }
+}
+
+impl fmt::Display for DurableSubscriptionName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for ConsumerWindowSize {
+    const NAME: &'static str = "consumer-window-size";
+
+    fn parse(value: &'a str) -> Option<ConsumerWindowSize> {
+        value.parse().ok().map(ConsumerWindowSize)
+    }
+}
+
+impl fmt::Display for ConsumerWindowSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for RoutingType {
+    const NAME: &'static str = "destination-type";
+
+    fn parse(value: &'a str) -> Option<RoutingType> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "ANYCAST" => Some(RoutingType::Anycast),
+            "MULTICAST" => Some(RoutingType::Multicast),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RoutingType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RoutingType::Anycast => "ANYCAST",
+            RoutingType::Multicast => "MULTICAST",
+        })
+    }
+}
+
+#[test]
+fn artemis_headers() {
+    use crate::header::HeaderList;
+
+    let mut headers = HeaderList::new();
+    headers.set_typed(RoutingType::Multicast);
+    headers.set_typed(DurableSubscriptionName("audit"));
+    headers.set_typed(ConsumerWindowSize(-1));
+    let raw: Vec<String> = headers.iter().map(|h| h.get_raw()).collect();
+    assert_eq!(
+        raw,
+        vec![
+            "destination-type:MULTICAST",
+            "durable-subscription-name:audit",
+            "consumer-window-size:-1",
+        ]
+    );
+    assert_eq!(headers.typed::<RoutingType>(), Some(RoutingType::Multicast));
+
+    assert_eq!(address("orders").to_string(), "orders");
+    assert_eq!(
+        fully_qualified_queue("prices", "eu").to_string(),
+        "prices::eu"
+    );
+}
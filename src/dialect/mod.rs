@@ -0,0 +1,109 @@
+//! Headers and destination names specific to particular brokers. Each module
+//! offers typed options for `MessageBuilder` and `SubscriptionBuilder` in
+//! place of stringly-typed `Header`s:
+//!
+//! ```ignore
+//! use stomp::dialect::activemq::{self, Persistent, PrefetchCount, Priority};
+//!
+//! session.message(activemq::queue("orders"), body)
+//!     .with(Persistent(true))
+//!     .with(Priority(7))
+//!     .send();
+//! session.subscription(activemq::topic("prices"))
+//!     .with(PrefetchCount(100))
+//!     .start();
+//! ```
+use crate::header::TypedHeader;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod activemq;
+pub mod artemis;
+pub mod rabbitmq;
+
+/// Whether the broker should store a message so that it survives a broker
+/// restart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Persistent(pub bool);
+
+/// The priority of a message, from 0 (lowest) to 9 on ActiveMQ and Artemis.
+/// RabbitMQ honours priorities up to the queue's `x-max-priority`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Priority(pub u8);
+
+/// A SQL-92 conditional expression over message headers, e.g.
+/// `region = 'EU' AND priority > 4`. Only matching messages are delivered to
+/// the subscription.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Selector<'a>(pub &'a str);
+
+/// The time at which a message expires, in milliseconds since the Unix epoch.
+/// Expired messages are discarded by the broker; 0 means never.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expires(pub u64);
+
+impl Expires {
+    /// Expires `ttl` from now.
+    pub fn after(ttl: Duration) -> Expires {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Expires((now + ttl).as_millis() as u64)
+    }
+}
+
+impl<'a> TypedHeader<'a> for Persistent {
+    const NAME: &'static str = "persistent";
+
+    fn parse(value: &'a str) -> Option<Persistent> {
+        value.parse().ok().map(Persistent)
+    }
+}
+
+impl fmt::Display for Persistent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for Priority {
+    const NAME: &'static str = "priority";
+
+    fn parse(value: &'a str) -> Option<Priority> {
+        value.parse().ok().map(Priority)
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for Selector<'a> {
+    const NAME: &'static str = "selector";
+
+    fn parse(value: &'a str) -> Option<Selector<'a>> {
+        Some(Selector(value))
+    }
+}
+
+impl fmt::Display for Selector<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for Expires {
+    const NAME: &'static str = "expires";
+
+    fn parse(value: &'a str) -> Option<Expires> {
+        value.parse().ok().map(Expires)
+    }
+}
+
+impl fmt::Display for Expires {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
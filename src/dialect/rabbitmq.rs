@@ -0,0 +1,159 @@
+//! RabbitMQ's STOMP plugin. Besides `/queue/` and `/topic/` destinations,
+//! messages can be sent to any exchange with a routing key, and consumed
+//! from queues created outside STOMP.
+use crate::destination::Destination;
+use crate::header::{Header, HeaderList, TypedHeader};
+use std::fmt;
+use std::time::Duration;
+
+pub use super::{Persistent, Priority};
+
+/// How long a message may wait in a queue before it is discarded, sent as
+/// the `expiration` header in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expires(pub Duration);
+
+/// Makes a topic or exchange subscription durable, backed by a queue of this
+/// name which is kept while the subscriber is disconnected.
+/// `Unsubscribe::Destroy` deletes the queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DurableSubscriptionName<'a>(pub &'a str);
+
+/// The type of the queue declared for a subscription.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XQueueType {
+    Classic,
+    Quorum,
+    Stream,
+}
+
+/// The number of unacknowledged messages the broker delivers to the
+/// subscription. Only applies to the `client` and `client-individual`
+/// acknowledgement modes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefetchCount(pub u16);
+
+pub fn queue(name: &str) -> Destination {
+    Destination::Queue(name.to_owned())
+}
+
+pub fn topic(name: &str) -> Destination {
+    Destination::Topic(name.to_owned())
+}
+
+/// The exchange `name`, with messages sent to or consumed from it using
+/// `routing_key`. An empty routing key is omitted.
+pub fn exchange(name: &str, routing_key: &str) -> Destination {
+    Destination::Exchange {
+        name: name.to_owned(),
+        routing_key: Some(routing_key)
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned),
+    }
+}
+
+/// A queue which was not declared through STOMP.
+pub fn amq_queue(name: &str) -> Destination {
+    Destination::AmqQueue(name.to_owned())
+}
+
+/// A queue private to the connection, e.g. to receive replies.
+pub fn temp_queue(name: &str) -> Destination {
+    Destination::TempQueue(name.to_owned())
+}
+
+impl DurableSubscriptionName<'_> {
+    // The headers of both the SUBSCRIBE frame and the UNSUBSCRIBE frame which
+    // destroys the subscription
+    pub(crate) fn headers(self) -> HeaderList {
+        let mut headers = HeaderList::with_capacity(3);
+        headers.push(Header::new("durable", "true"));
+        headers.push(Header::new("auto-delete", "false"));
+        headers.push(Header::new("x-queue-name", self.0));
+        headers
+    }
+}
+
+impl<'a> TypedHeader<'a> for Expires {
+    const NAME: &'static str = "expiration";
+
+    fn parse(value: &'a str) -> Option<Expires> {
+        value.parse().ok().map(Duration::from_millis).map(Expires)
+    }
+}
+
+impl fmt::Display for Expires {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.as_millis())
+    }
+}
+
+impl<'a> TypedHeader<'a> for XQueueType {
+    const NAME: &'static str = "x-queue-type";
+
+    fn parse(value: &'a str) -> Option<XQueueType> {
+        match value {
+            "classic" => Some(XQueueType::Classic),
+            "quorum" => Some(XQueueType::Quorum),
+            "stream" => Some(XQueueType::Stream),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for XQueueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            XQueueType::Classic => "classic",
+            XQueueType::Quorum => "quorum",
+            XQueueType::Stream => "stream",
+        })
+    }
+}
+
+impl<'a> TypedHeader<'a> for PrefetchCount {
+    const NAME: &'static str = "prefetch-count";
+
+    fn parse(value: &'a str) -> Option<PrefetchCount> {
+        value.parse().ok().map(PrefetchCount)
+    }
+}
+
+impl fmt::Display for PrefetchCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[test]
+fn rabbitmq_headers() {
+    let mut headers = HeaderList::new();
+    headers.set_typed(Expires(Duration::from_secs(60)));
+    headers.set_typed(XQueueType::Quorum);
+    headers.set_typed(PrefetchCount(50));
+    headers.concat(&mut DurableSubscriptionName("audit").headers());
+    let raw: Vec<String> = headers.iter().map(|h| h.get_raw()).collect();
+    assert_eq!(
+        raw,
+        vec![
+            "expiration:60000",
+            "x-queue-type:quorum",
+            "prefetch-count:50",
+            "durable:true",
+            "auto-delete:false",
+            "x-queue-name:audit",
+        ]
+    );
+    assert_eq!(
+        headers.typed::<Expires>(),
+        Some(Expires(Duration::from_secs(60)))
+    );
+
+    assert_eq!(
+        exchange("amq.direct", "orders").to_string(),
+        "/exchange/amq.direct/orders"
+    );
+    assert_eq!(exchange("events", "").to_string(), "/exchange/events");
+    assert_eq!(amq_queue("legacy").to_string(), "/amq/queue/legacy");
+    assert_eq!(temp_queue("replies").to_string(), "/temp-queue/replies");
+}
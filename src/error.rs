@@ -0,0 +1,182 @@
+use crate::codec::LimitExceeded;
+use crate::frame::Frame;
+use crate::header;
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// An ERROR frame sent by the broker.
+#[derive(Clone, Debug)]
+pub struct BrokerError {
+    /// The short description from the `message` header.
+    pub message: Option<String>,
+    /// The `receipt-id` header, present when the error was caused by a frame
+    /// that requested a receipt.
+    pub receipt_id: Option<String>,
+    /// The frame that requested `receipt_id`, if it was sent by this session.
+    /// A message sent with `Session::message_stream` is kept without its body
+    /// or `content-length` header.
+    pub original: Option<Frame>,
+    pub frame: Frame,
+}
+
+impl BrokerError {
+    pub fn new(frame: Frame) -> BrokerError {
+        BrokerError {
+            message: frame
+                .headers
+                .get_message()
+                .map(|header::Message(message)| message.to_owned()),
+            receipt_id: frame
+                .headers
+                .get_receipt_id()
+                .map(|header::ReceiptId(id)| id.to_owned()),
+            original: None,
+            frame,
+        }
+    }
+
+    /// The detailed description carried in the body, if the body is textual.
+    /// Bodies are considered textual when they have no `content-type` or a
+    /// textual one, and can be decoded in its charset.
+    pub fn body_text(&self) -> Option<Cow<'_, str>> {
+        let textual = match self.frame.headers.get_content_type() {
+            Some(content_type) => content_type
+                .media_type()
+                .map(|media_type| media_type.is_text())
+                .unwrap_or(false),
+            None => true,
+        };
+        if !textual || self.frame.body.is_empty() {
+            return None;
+        }
+        self.frame.text().ok()
+    }
+
+    /// Whether the connection should be considered lost. STOMP 1.2 requires
+    /// the broker to close the connection after every ERROR frame, but some
+    /// brokers (e.g. ActiveMQ) keep it open when the error only answers a
+    /// frame that requested a receipt.
+    pub fn is_fatal(&self) -> bool {
+        self.receipt_id.is_none()
+    }
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message {
+            Some(ref message) => write!(f, "broker error: {}", message)?,
+            None => write!(f, "broker error")?,
+        }
+        if let Some(ref original) = self.original {
+            write!(f, " (in reply to {})", original.command)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for BrokerError {}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the connection failed.
+    Io(io::Error),
+    /// The broker sent bytes that could not be parsed as a STOMP transmission.
+    /// `offset` is the position of the offending byte from the start of the
+    /// frame being decoded.
+    Protocol { offset: usize, message: String },
+    /// The broker sent a frame breaking one of the configured `FrameLimits`.
+    LimitExceeded(LimitExceeded),
+    /// The broker answered with an ERROR frame.
+    Broker(Box<BrokerError>),
+    /// No RECEIPT arrived in time for the receipt with the given id.
+    ReceiptTimeout(String),
+    /// No reply arrived in time for the request with the given
+    /// `correlation-id`.
+    RequestTimeout(String),
+    /// The broker stopped sending heart-beats.
+    HeartbeatTimeout,
+    /// The connection was lost before the broker answered.
+    Disconnected,
+    /// The client API was used in a way that cannot be honoured.
+    InvalidUsage(String),
+    /// A message body could not be serialized or deserialized.
+    Payload(Box<dyn error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Protocol {
+                offset,
+                ref message,
+            } => write!(f, "protocol error at byte {}: {}", offset, message),
+            Error::LimitExceeded(ref limit) => write!(f, "limit exceeded: {}", limit),
+            Error::Broker(ref error) => error.fmt(f),
+            Error::ReceiptTimeout(ref id) => write!(f, "timed out waiting for receipt '{}'", id),
+            Error::RequestTimeout(ref id) => write!(f, "timed out waiting for reply to '{}'", id),
+            Error::HeartbeatTimeout => write!(f, "timed out waiting for a heart-beat"),
+            Error::Disconnected => write!(f, "disconnected before the broker answered"),
+            Error::InvalidUsage(ref message) => write!(f, "invalid usage: {}", message),
+            Error::Payload(ref error) => write!(f, "invalid payload: {}", error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            Error::LimitExceeded(ref limit) => Some(limit),
+            Error::Broker(ref error) => Some(&**error),
+            Error::Payload(ref error) => Some(&**error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<BrokerError> for Error {
+    fn from(error: BrokerError) -> Error {
+        Error::Broker(Box::new(error))
+    }
+}
+
+impl From<LimitExceeded> for Error {
+    fn from(limit: LimitExceeded) -> Error {
+        Error::LimitExceeded(limit)
+    }
+}
+
+#[test]
+fn broker_error_from_frame() {
+    use crate::frame::Command;
+    use crate::header::{Header, HeaderList};
+
+    let frame = Frame {
+        command: Command::Error,
+        headers: header_list![
+            "message" => "malformed frame received",
+            "receipt-id" => "message/12",
+            "content-type" => "text/plain"
+        ],
+        body: b"The message body was too long".to_vec(),
+    };
+    let error = BrokerError::new(frame);
+    assert_eq!(error.message.as_deref(), Some("malformed frame received"));
+    assert_eq!(error.receipt_id.as_deref(), Some("message/12"));
+    assert_eq!(
+        error.body_text().as_deref(),
+        Some("The message body was too long")
+    );
+    assert!(!error.is_fatal());
+}
@@ -0,0 +1,150 @@
+//! Escaping of header keys and values, as described in the "Value Encoding"
+//! section of the STOMP 1.2 specification. Frames other than CONNECT and
+//! CONNECTED represent a carriage return, line feed, colon and backslash in
+//! their headers as `\r`, `\n`, `\c` and `\\` respectively.
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+
+/// How `decode` treats a backslash which does not start one of the four
+/// escape sequences defined by the specification.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DecodeMode {
+    /// Fail, as the specification requires.
+    #[default]
+    Strict,
+    /// Keep the backslash and the character following it as they are, for
+    /// brokers which do not escape their headers.
+    Lenient,
+}
+
+/// An escape sequence not defined by the specification. `position` is the
+/// byte offset of the backslash within the encoded string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidEscape {
+    pub position: usize,
+    pub sequence: String,
+}
+
+impl fmt::Display for InvalidEscape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "undefined escape sequence '{}' at byte {}",
+            self.sequence, self.position
+        )
+    }
+}
+
+impl error::Error for InvalidEscape {}
+
+fn needs_escaping(c: char) -> bool {
+    c == '\\' || c == '\r' || c == '\n' || c == ':'
+}
+
+/// Escapes `value` for use in a header. Returns `value` itself when there is
+/// nothing to escape.
+pub fn encode(value: &str) -> Cow<'_, str> {
+    if !value.contains(needs_escaping) {
+        return Cow::Borrowed(value);
+    }
+    let mut encoded = String::with_capacity(value.len() + 8);
+    for c in value.chars() {
+        match c {
+            '\\' => encoded.push_str(r"\\"),
+            '\r' => encoded.push_str(r"\r"),
+            '\n' => encoded.push_str(r"\n"),
+            ':' => encoded.push_str(r"\c"),
+            c => encoded.push(c),
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+/// Reverses `encode`. Returns `value` itself when it contains no escape
+/// sequences.
+pub fn decode(value: &str, mode: DecodeMode) -> Result<Cow<'_, str>, InvalidEscape> {
+    if !value.contains('\\') {
+        return Ok(Cow::Borrowed(value));
+    }
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.char_indices();
+    while let Some((position, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, '\\')) => decoded.push('\\'),
+            Some((_, 'r')) => decoded.push('\r'),
+            Some((_, 'n')) => decoded.push('\n'),
+            Some((_, 'c')) => decoded.push(':'),
+            other => {
+                if mode == DecodeMode::Strict {
+                    let mut sequence = "\\".to_owned();
+                    sequence.extend(other.map(|(_, c)| c));
+                    return Err(InvalidEscape { position, sequence });
+                }
+                decoded.push('\\');
+                decoded.extend(other.map(|(_, c)| c));
+            }
+        }
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+#[test]
+fn decode_spec_sequences() {
+    let decoded = decode(r"a\\b\rc\nd\ce", DecodeMode::Strict).unwrap();
+    assert_eq!(decoded, "a\\b\rc\nd:e");
+    assert!(matches!(
+        decode("plain", DecodeMode::Strict),
+        Ok(Cow::Borrowed(_))
+    ));
+}
+
+#[test]
+fn decode_undefined_escapes() {
+    assert_eq!(
+        decode(r"tab\there", DecodeMode::Strict),
+        Err(InvalidEscape {
+            position: 3,
+            sequence: r"\t".to_owned(),
+        })
+    );
+    assert_eq!(
+        decode(r"trailing\", DecodeMode::Strict)
+            .unwrap_err()
+            .sequence,
+        r"\"
+    );
+    assert_eq!(
+        decode(r"tab\there", DecodeMode::Lenient).unwrap(),
+        r"tab\there"
+    );
+    assert_eq!(
+        decode(r"trailing\", DecodeMode::Lenient).unwrap(),
+        r"trailing\"
+    );
+}
+
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn decode_reverses_encode(value in any::<String>()) {
+            let encoded = encode(&value);
+            prop_assert!(!encoded.contains(['\r', '\n', ':']));
+            prop_assert_eq!(decode(&encoded, DecodeMode::Strict).unwrap(), value.as_str());
+        }
+
+        #[test]
+        fn encode_reverses_decode(value in r"([^\\\r\n:]|\\[\\rnc])*") {
+            let decoded = decode(&value, DecodeMode::Strict).unwrap();
+            prop_assert_eq!(encode(&decoded), value.as_str());
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::header::Header;
+use crate::destination::Destination;
+use crate::error::{Error, Result};
 use crate::header::HeaderList;
-use crate::subscription::AckMode;
+use crate::header::{self, Header};
+use crate::subscription::{AckMode, AckOrNack};
 use bytes::BytesMut;
+use std::borrow::Cow;
 use std::fmt;
 use std::fmt::Formatter;
 use std::str::from_utf8;
@@ -47,6 +50,14 @@ impl Command {
         }
     }
 }
+impl Command {
+    /// Whether header keys and values are escaped in frames with this
+    /// command. CONNECT and CONNECTED frames are exempt, for compatibility
+    /// with STOMP 1.0 peers.
+    pub fn escapes_headers(self) -> bool {
+        !matches!(self, Command::Connect | Command::Connected)
+    }
+}
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.as_str())
@@ -86,6 +97,11 @@ pub struct Frame {
 pub enum Transmission {
     HeartBeat,
     CompleteFrame(Frame),
+    /// The command and headers of a frame whose body follows as a series of
+    /// `BodyChunk`s terminated by `BodyEnd`. The frame's own body is empty.
+    FrameHead(Frame),
+    BodyChunk(Vec<u8>),
+    BodyEnd,
 }
 
 impl Transmission {
@@ -93,63 +109,144 @@ impl Transmission {
         match *self {
             Transmission::HeartBeat => out.extend("\n".as_bytes()),
             Transmission::CompleteFrame(ref frame) => frame.write(out),
+            Transmission::FrameHead(ref frame) => frame.write_head(out),
+            Transmission::BodyChunk(ref chunk) => out.extend(chunk),
+            Transmission::BodyEnd => out.extend(&[0]),
         }
     }
 }
 
+// The number of bytes of a binary body shown when displaying a frame
+const HEX_PREVIEW_LENGTH: usize = 32;
+
 impl fmt::Display for Frame {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_str())
+        writeln!(f, "{}", self.command)?;
+        for header in self.headers.iter() {
+            writeln!(f, "{}", self.raw_header(header))?;
+        }
+        writeln!(f)?;
+        if let Ok(text) = self.text() {
+            return f.write_str(&text);
+        }
+        write!(f, "<{} bytes of binary content:", self.body.len())?;
+        for byte in self.body.iter().take(HEX_PREVIEW_LENGTH) {
+            write!(f, " {:02x}", byte)?;
+        }
+        if self.body.len() > HEX_PREVIEW_LENGTH {
+            write!(f, " ...")?;
+        }
+        write!(f, ">")
+    }
+}
+
+// Decodes a body in the given charset.
+fn decode_text<'a>(body: &'a [u8], charset: &str) -> Result<Cow<'a, str>> {
+    let malformed = || Error::Payload(format!("body is not valid {}", charset).into());
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" | "utf8" => from_utf8(body).map(Cow::Borrowed).map_err(|_| malformed()),
+        "us-ascii" | "ascii" => {
+            if !body.is_ascii() {
+                return Err(malformed());
+            }
+            Ok(Cow::Borrowed(from_utf8(body).unwrap()))
+        }
+        "iso-8859-1" | "iso_8859-1" | "latin1" | "l1" => {
+            Ok(Cow::Owned(body.iter().map(|&b| char::from(b)).collect()))
+        }
+        charset @ ("utf-16" | "utf-16be" | "utf-16le") => {
+            if body.len() % 2 != 0 {
+                return Err(malformed());
+            }
+            let (little_endian, body) = match (charset, body) {
+                ("utf-16", [0xff, 0xfe, rest @ ..]) => (true, rest),
+                ("utf-16", [0xfe, 0xff, rest @ ..]) => (false, rest),
+                _ => (charset == "utf-16le", body),
+            };
+            let units = body.chunks(2).map(|pair| {
+                if little_endian {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                }
+            });
+            char::decode_utf16(units)
+                .collect::<::std::result::Result<String, _>>()
+                .map(Cow::Owned)
+                .map_err(|_| malformed())
+        }
+        _ => Err(Error::Payload(
+            format!("unsupported charset '{}'", charset).into(),
+        )),
     }
 }
 
 impl Frame {
+    // `header` as it is written in this frame
+    fn raw_header(&self, header: &Header) -> String {
+        if self.command.escapes_headers() {
+            self.headers.escaped(header).get_raw()
+        } else {
+            header.get_raw()
+        }
+    }
+
     pub fn count_bytes(&self) -> usize {
         let mut space_required: usize = 0;
         // Add one to space calculations to make room for '\n'
         space_required += self.command.as_str().len() + 1;
-        space_required += self
-            .headers
-            .iter()
-            .fold(0, |length, header| length + header.get_raw().len() + 1);
+        space_required += self.headers.iter().fold(0, |length, header| {
+            length + self.raw_header(header).len() + 1
+        });
         space_required += 1; // Newline at end of headers
         space_required += self.body.len();
         space_required
     }
 
     pub fn to_str(&self) -> String {
-        let space_required = self.count_bytes();
-        let mut frame_string = String::with_capacity(space_required); // Faster to just allocate?
-        frame_string.push_str(self.command.as_str());
-        frame_string.push_str("\n");
-        for header in self.headers.iter() {
-            frame_string.push_str(&header.get_raw());
-            frame_string.push_str("\n");
-        }
-        frame_string.push_str("\n");
-        let body_string: &str = match from_utf8(self.body.as_ref()) {
-            Ok(ref s) => *s,
-            Err(_) => "<Binary content>", // Space is wasted in this case. Could shrink to fit?
+        self.to_string()
+    }
+
+    /// The body decoded as text, in the charset given by the `content-type`
+    /// header or UTF-8 if there is none. UTF-8, US-ASCII, ISO-8859-1 and
+    /// UTF-16 are supported.
+    pub fn text(&self) -> Result<Cow<'_, str>> {
+        let media_type = match self.headers.get_content_type() {
+            Some(content_type) => Some(
+                content_type
+                    .media_type()
+                    .map_err(|e| Error::Payload(e.into()))?,
+            ),
+            None => None,
         };
-        frame_string.push_str(body_string);
-        frame_string
+        let charset = media_type
+            .as_ref()
+            .and_then(|media_type| media_type.charset())
+            .unwrap_or("utf-8");
+        decode_text(&self.body, charset)
     }
 
     pub fn write(&self, out: &mut BytesMut) {
         debug!("Sending frame:\n{}", self.to_str());
+        self.write_head(out);
+        out.extend(&self.body);
+
+        out.extend(&[0]);
+        debug!("write() complete.");
+    }
+
+    /// Writes the command and headers, up to and including the blank line
+    /// which precedes the body.
+    pub fn write_head(&self, out: &mut BytesMut) {
         out.extend(self.command.as_str().as_bytes());
         out.extend("\n".as_bytes());
 
         for header in self.headers.iter() {
-            out.extend(header.get_raw().as_bytes());
+            out.extend(self.raw_header(header).as_bytes());
             out.extend("\n".as_bytes());
         }
 
         out.extend("\n".as_bytes());
-        out.extend(&self.body);
-
-        out.extend(&[0]);
-        debug!("write() complete.");
     }
 
     pub fn connect(tx_heartbeat_ms: u32, rx_heartbeat_ms: u32) -> Frame {
@@ -175,11 +272,15 @@ impl Frame {
         }
     }
 
-    pub fn subscribe(subscription_id: &str, destination: &str, ack_mode: AckMode) -> Frame {
+    pub fn subscribe<D: Into<Destination>>(
+        subscription_id: &str,
+        destination: D,
+        ack_mode: AckMode,
+    ) -> Frame {
         Frame {
             command: Command::Subscribe,
             headers: header_list![
-                "destination" => destination,
+                "destination" => &destination.into().to_string(),
                 "id" => subscription_id,
                 "ack" => ack_mode.as_text()
             ],
@@ -217,11 +318,23 @@ impl Frame {
         }
     }
 
-    pub fn send(destination: &str, body: &[u8]) -> Frame {
+    /// The ACK or NACK frame for a received MESSAGE, or `None` if the
+    /// message does not need to be acknowledged.
+    pub fn acknowledgement(message: &Frame, which: AckOrNack) -> Option<Frame> {
+        message
+            .headers
+            .get_ack()
+            .map(|header::Ack(ack_id)| match which {
+                AckOrNack::Ack => Frame::ack(ack_id),
+                AckOrNack::Nack => Frame::nack(ack_id),
+            })
+    }
+
+    pub fn send<D: Into<Destination>>(destination: D, body: &[u8]) -> Frame {
         Frame {
             command: Command::Send,
             headers: header_list![
-                "destination" => destination,
+                "destination" => &destination.into().to_string(),
                 "content-length" => body.len().to_string().as_ref()
             ],
             body: body.into(),
@@ -258,3 +371,55 @@ impl Frame {
         }
     }
 }
+
+#[test]
+fn acknowledgement_frames() {
+    let message = Frame {
+        command: Command::Message,
+        headers: header_list![
+            "message-id" => "7",
+            "ack" => "ack/7"
+        ],
+        body: Vec::new(),
+    };
+    let ack = Frame::acknowledgement(&message, AckOrNack::Ack).unwrap();
+    assert_eq!(ack.command.as_str(), "ACK");
+    assert_eq!(ack.headers.get_header("id").unwrap().get_value(), "ack/7");
+    let nack = Frame::acknowledgement(&message, AckOrNack::Nack).unwrap();
+    assert_eq!(nack.command.as_str(), "NACK");
+
+    let unacknowledged = Frame::send("/queue/a", b"");
+    assert!(Frame::acknowledgement(&unacknowledged, AckOrNack::Ack).is_none());
+}
+
+#[test]
+fn decode_text_in_charset() {
+    let mut frame = Frame::send("/queue/a", b"caf\xe9");
+    assert!(frame.text().is_err());
+    frame
+        .headers
+        .push(Header::new("content-type", "text/plain;charset=ISO-8859-1"));
+    assert_eq!(frame.text().unwrap(), "caf\u{e9}");
+
+    let mut utf16 = Frame::send("/queue/a", b"\xff\xfeh\x00i\x00");
+    utf16.headers.push(Header::new(
+        "content-type",
+        "text/plain; charset=\"UTF-16\"",
+    ));
+    assert_eq!(utf16.text().unwrap(), "hi");
+
+    let mut unknown = Frame::send("/queue/a", b"hi");
+    unknown
+        .headers
+        .push(Header::new("content-type", "text/plain;charset=koi8-r"));
+    assert!(unknown.text().is_err());
+}
+
+#[test]
+fn display_binary_body() {
+    let frame = Frame::send("/queue/a", &[0x89, b'P', b'N', b'G', 0xff]);
+    assert_eq!(
+        frame.to_string(),
+        "SEND\ndestination:/queue/a\ncontent-length:5\n\n<5 bytes of binary content: 89 50 4e 47 ff>"
+    );
+}
@@ -1,8 +1,13 @@
 // Non-camel case types are used for Stomp Protocol version enum variants
 #![macro_use]
 #![allow(non_camel_case_types)]
+use crate::escape;
+use crate::media_type::{InvalidMediaType, MediaType};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
 use std::slice::Iter;
-use unicode_segmentation::UnicodeSegmentation;
+use std::str::FromStr;
 
 // Ideally this would be a simple typedef. However:
 // See Rust bug #11047: https://github.com/mozilla/rust/issues/11047
@@ -10,6 +15,9 @@ use unicode_segmentation::UnicodeSegmentation;
 #[derive(Clone, Debug, Default)]
 pub struct HeaderList {
     pub headers: Vec<Header>,
+    // Whether the headers were received, and so hold unescaped keys and values
+    // rather than escaped ones
+    decoded: bool,
 }
 
 impl HeaderList {
@@ -19,6 +27,26 @@ impl HeaderList {
     pub fn with_capacity(capacity: usize) -> HeaderList {
         HeaderList {
             headers: Vec::with_capacity(capacity),
+            decoded: false,
+        }
+    }
+
+    // A list for the headers of a received frame, which are decoded
+    pub(crate) fn decoded() -> HeaderList {
+        HeaderList {
+            headers: Vec::new(),
+            decoded: true,
+        }
+    }
+
+    // `header`, one of these headers, with its key and value escaped as they
+    // are sent in frames other than CONNECT. Received headers are decoded, so
+    // are escaped again; others already are.
+    pub(crate) fn escaped<'a>(&self, header: &'a Header) -> Cow<'a, Header> {
+        if self.decoded {
+            Cow::Owned(Header::new(&header.0, &header.1))
+        } else {
+            Cow::Borrowed(header)
         }
     }
 
@@ -30,24 +58,19 @@ impl HeaderList {
         self.headers.pop()
     }
 
-    pub fn iter(&self) -> Iter<Header> {
+    pub fn iter(&self) -> Iter<'_, Header> {
         self.headers.iter()
     }
 
-    pub fn drain<F>(&mut self, mut sink: F)
+    pub fn drain<F>(&mut self, sink: F)
     where
         F: FnMut(Header),
     {
-        while let Some(header) = self.headers.pop() {
-            sink(header);
-        }
+        self.headers.drain(..).for_each(sink);
     }
 
     pub fn concat(&mut self, other_list: &mut HeaderList) {
-        other_list.headers.reverse();
-        while let Some(header) = other_list.pop() {
-            self.headers.push(header);
-        }
+        self.headers.append(&mut other_list.headers);
     }
 
     pub fn retain<F>(&mut self, test: F)
@@ -56,18 +79,112 @@ impl HeaderList {
     {
         self.headers.retain(test)
     }
+
+    /// Sets the value of `header`'s key. The first header with that key is
+    /// replaced in place and returned, and any repetitions are removed; if
+    /// there was none, `header` is added at the end.
+    pub fn insert(&mut self, header: Header) -> Option<Header> {
+        match self.headers.iter().position(|h| h.get_key() == header.get_key()) {
+            Some(index) => {
+                let key = header.get_key().to_owned();
+                let replaced = ::std::mem::replace(&mut self.headers[index], header);
+                let mut position = 0;
+                self.headers.retain(|h| {
+                    position += 1;
+                    position - 1 <= index || h.get_key() != key
+                });
+                Some(replaced)
+            }
+            None => {
+                self.headers.push(header);
+                None
+            }
+        }
+    }
+
+    /// Adds `header` at the end, even if its key is already present. Only the
+    /// first occurrence of a repeated header is honoured by STOMP 1.2 peers.
+    pub fn append(&mut self, header: Header) {
+        self.headers.push(header);
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Header> + 'a {
+        self.headers.iter().filter(move |h| h.get_key() == key)
+    }
+
+    /// Removes all headers with the given key, returning the first of them.
+    pub fn remove(&mut self, key: &str) -> Option<Header> {
+        let index = self.headers.iter().position(|h| h.get_key() == key)?;
+        let removed = self.headers.remove(index);
+        self.headers.retain(|h| h.get_key() != key);
+        Some(removed)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.headers.iter().any(|h| h.get_key() == key)
+    }
+
+    /// Resolves repeated keys according to `policy`, failing with the first
+    /// repeated key if the policy is `Reject`.
+    pub fn apply_policy(&mut self, policy: DuplicateHeaderPolicy) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        match policy {
+            DuplicateHeaderPolicy::Allow => {}
+            DuplicateHeaderPolicy::KeepFirst => {
+                self.headers.retain(|h| seen.insert(h.get_key().to_owned()));
+            }
+            DuplicateHeaderPolicy::KeepLast => {
+                self.headers.reverse();
+                self.headers.retain(|h| seen.insert(h.get_key().to_owned()));
+                self.headers.reverse();
+            }
+            DuplicateHeaderPolicy::Reject => {
+                if let Some(h) = self.headers.iter().find(|h| !seen.insert(h.get_key().to_owned())) {
+                    return Err(h.get_key().to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How repeated headers in outgoing frames are handled. STOMP 1.2 peers only
+/// honour the first occurrence of a header, so a header added through a
+/// builder's `with` is ignored if the crate already set the same key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicateHeaderPolicy {
+    /// Send frames as they were built.
+    #[default]
+    Allow,
+    /// Drop repetitions, keeping the occurrence the broker would honour.
+    KeepFirst,
+    /// Keep only the last occurrence, so headers added by the application
+    /// override those set by the crate.
+    KeepLast,
+    /// Refuse to send frames containing repeated headers.
+    Reject,
 }
 
 pub struct SuppressedHeader<'a>(pub &'a str);
 pub struct ContentType<'a>(pub &'a str);
+
+impl ContentType<'_> {
+    pub fn media_type(&self) -> Result<MediaType, InvalidMediaType> {
+        self.0.parse()
+    }
+}
 #[derive(Clone, Debug)]
 pub struct Header(pub String, pub String);
 
 impl Header {
+    /// Creates a header from an unescaped key and value, escaping them right
+    /// away: `get_value` returns the escaped value, which is sent as it is,
+    /// even in CONNECT frames.
     pub fn new(key: &str, value: &str) -> Header {
         Header(Self::encode_value(key), Self::encode_value(value))
     }
 
+    /// Creates a header which is sent exactly as given.
     pub fn new_raw<T: Into<String>, U: Into<String>>(key: T, value: U) -> Header {
         Header(key.into(), value.into())
     }
@@ -77,17 +194,7 @@ impl Header {
     }
 
     pub fn encode_value(value: &str) -> String {
-        let mut encoded = String::new(); //self.strings.detached();
-        for grapheme in UnicodeSegmentation::graphemes(value, true) {
-            match grapheme {
-                "\\" => encoded.push_str(r"\\"), // Order is significant
-                "\r" => encoded.push_str(r"\r"),
-                "\n" => encoded.push_str(r"\n"),
-                ":" => encoded.push_str(r"\c"),
-                g => encoded.push_str(g),
-            }
-        }
-        encoded
+        escape::encode(value).into_owned()
     }
 
     pub fn get_key(&self) -> &str {
@@ -99,6 +206,59 @@ impl Header {
     }
 }
 
+/// A header with a well-known key whose value can be parsed into, and encoded
+/// from, a Rust type. All headers defined by the STOMP specification implement
+/// it; applications can implement it for their own headers and use them with
+/// `HeaderList::typed` and `HeaderList::set_typed`:
+///
+/// ```
+/// use std::fmt;
+/// use stomp::header::{HeaderList, TypedHeader};
+///
+/// struct Priority(u8);
+///
+/// impl fmt::Display for Priority {
+///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+///         write!(f, "{}", self.0)
+///     }
+/// }
+///
+/// impl<'a> TypedHeader<'a> for Priority {
+///     const NAME: &'static str = "priority";
+///     fn parse(value: &'a str) -> Option<Priority> {
+///         value.parse().ok().map(Priority)
+///     }
+/// }
+///
+/// let mut headers = HeaderList::new();
+/// headers.set_typed(Priority(9));
+/// assert_eq!(headers.typed::<Priority>().map(|Priority(p)| p), Some(9));
+/// ```
+pub trait TypedHeader<'a>: Sized + fmt::Display {
+    const NAME: &'static str;
+
+    fn parse(value: &'a str) -> Option<Self>;
+
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// A header value which could not be parsed as the typed header `name`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidHeaderValue {
+    pub name: &'static str,
+    pub value: String,
+}
+
+impl fmt::Display for InvalidHeaderValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid value for header '{}': '{}'", self.name, self.value)
+    }
+}
+
+impl ::std::error::Error for InvalidHeaderValue {}
+
 // Headers in the Spec
 #[derive(Clone)]
 pub struct AcceptVersion(pub Vec<StompVersion>);
@@ -112,6 +272,7 @@ pub struct HeartBeat(pub u32, pub u32);
 pub struct Host<'a>(pub &'a str);
 pub struct Id<'a>(pub &'a str);
 pub struct Login<'a>(pub &'a str);
+pub struct Message<'a>(pub &'a str);
 pub struct MessageId<'a>(pub &'a str);
 pub struct Passcode<'a>(pub &'a str);
 pub struct Receipt<'a>(pub &'a str);
@@ -123,169 +284,290 @@ pub struct Transaction<'a>(pub &'a str);
 #[derive(Clone, Copy)]
 pub struct Version(pub StompVersion);
 
-#[derive(Clone, Copy)]
+// Headers outside the Spec which are understood by most brokers
+pub struct ClientId<'a>(pub &'a str);
+pub struct CorrelationId<'a>(pub &'a str);
+pub struct ReplyTo<'a>(pub &'a str);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StompVersion {
     Stomp_v1_0,
     Stomp_v1_1,
     Stomp_v1_2,
 }
 
-impl HeaderList {
-    pub fn get_header(&self, key: &str) -> Option<&Header> {
-        self.headers.iter().find(|header| match **header {
-            ref h if h.get_key() == key => true,
-            _ => false,
-        })
+impl StompVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StompVersion::Stomp_v1_0 => "1.0",
+            StompVersion::Stomp_v1_1 => "1.1",
+            StompVersion::Stomp_v1_2 => "1.2",
+        }
     }
+}
 
-    pub fn get_accept_version(&self) -> Option<Vec<StompVersion>> {
-        let versions: &str = match self.get_header("accept-version") {
-            Some(h) => h.get_value(),
-            None => return None,
-        };
-        let versions: Vec<StompVersion> = versions
-            .split(',')
-            .filter_map(|v| match v.trim() {
-                "1.0" => Some(StompVersion::Stomp_v1_0),
-                "1.1" => Some(StompVersion::Stomp_v1_1),
-                "1.2" => Some(StompVersion::Stomp_v1_2),
-                _ => None,
-            })
-            .collect();
-        Some(versions)
+impl fmt::Display for StompVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
     }
+}
+
+impl FromStr for StompVersion {
+    type Err = InvalidHeaderValue;
 
-    pub fn get_ack(&self) -> Option<Ack> {
-        match self.get_header("ack") {
-            Some(h) => Some(Ack(h.get_value())),
-            None => None,
+    fn from_str(value: &str) -> Result<StompVersion, InvalidHeaderValue> {
+        match value.trim() {
+            "1.0" => Ok(StompVersion::Stomp_v1_0),
+            "1.1" => Ok(StompVersion::Stomp_v1_1),
+            "1.2" => Ok(StompVersion::Stomp_v1_2),
+            _ => Err(InvalidHeaderValue { name: Version::NAME, value: value.to_owned() }),
         }
     }
+}
 
-    pub fn get_destination(&self) -> Option<Destination> {
-        match self.get_header("destination") {
-            Some(h) => Some(Destination(h.get_value())),
-            None => None,
-        }
+// Headers whose value is used as-is
+macro_rules! str_headers {
+    ($($header: ident => $name: expr), *) => {
+        $(
+            impl<'a> TypedHeader<'a> for $header<'a> {
+                const NAME: &'static str = $name;
+
+                fn parse(value: &'a str) -> Option<$header<'a>> {
+                    Some($header(value))
+                }
+            }
+
+            impl<'a> fmt::Display for $header<'a> {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str(self.0)
+                }
+            }
+        )*
     }
+}
 
-    pub fn get_heart_beat(&self) -> Option<HeartBeat> {
-        let spec = match self.get_header("heart-beat") {
-            Some(h) => h.get_value(),
-            None => return None,
-        };
-        let mut spec_iter = spec
+str_headers! {
+    Ack => "ack",
+    ClientId => "client-id",
+    ContentType => "content-type",
+    CorrelationId => "correlation-id",
+    Destination => "destination",
+    Host => "host",
+    Id => "id",
+    Login => "login",
+    Message => "message",
+    MessageId => "message-id",
+    Passcode => "passcode",
+    Receipt => "receipt",
+    ReceiptId => "receipt-id",
+    ReplyTo => "reply-to",
+    Server => "server",
+    Session => "session",
+    Subscription => "subscription",
+    Transaction => "transaction"
+}
+
+// Headers with an owned value, which can also be parsed with `FromStr`
+macro_rules! owned_headers {
+    ($($header: ident), *) => {
+        $(
+            impl FromStr for $header {
+                type Err = InvalidHeaderValue;
+
+                fn from_str(value: &str) -> Result<$header, InvalidHeaderValue> {
+                    <$header as TypedHeader>::parse(value).ok_or_else(|| InvalidHeaderValue {
+                        name: $header::NAME,
+                        value: value.to_owned(),
+                    })
+                }
+            }
+        )*
+    }
+}
+
+owned_headers!(AcceptVersion, ContentLength, HeartBeat, Version);
+
+impl<'a> TypedHeader<'a> for AcceptVersion {
+    const NAME: &'static str = "accept-version";
+
+    fn parse(value: &'a str) -> Option<AcceptVersion> {
+        let versions = value
             .split(',')
-            .filter_map(|str_val| str_val.parse::<u32>().ok());
-        let x = spec_iter.next();
-        let y = spec_iter.next();
-        let z = spec_iter.next();
+            .filter_map(|v| v.parse::<StompVersion>().ok())
+            .collect();
+        Some(AcceptVersion(versions))
+    }
+}
+
+impl fmt::Display for AcceptVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let versions: Vec<&str> = self.0.iter().map(|v| v.as_str()).collect();
+        f.write_str(&versions.join(","))
+    }
+}
 
-        match (x, y, z) {
-            (Some(x), Some(y), None) => Some(HeartBeat(x, y)),
+impl<'a> TypedHeader<'a> for ContentLength {
+    const NAME: &'static str = "content-length";
+
+    fn parse(value: &'a str) -> Option<ContentLength> {
+        value.parse::<u32>().ok().map(ContentLength)
+    }
+}
+
+impl fmt::Display for ContentLength {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a> TypedHeader<'a> for HeartBeat {
+    const NAME: &'static str = "heart-beat";
+
+    fn parse(value: &'a str) -> Option<HeartBeat> {
+        let mut spec_iter = value
+            .split(',')
+            .map(|str_val| str_val.trim().parse::<u32>());
+        match (spec_iter.next(), spec_iter.next(), spec_iter.next()) {
+            (Some(Ok(x)), Some(Ok(y)), None) => Some(HeartBeat(x, y)),
             _ => None
         }
     }
+}
 
-    pub fn get_host(&self) -> Option<Host> {
-        match self.get_header("host") {
-            Some(h) => Some(Host(h.get_value())),
-            None => None,
-        }
+impl fmt::Display for HeartBeat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.0, self.1)
     }
+}
 
-    pub fn get_id(&self) -> Option<Id> {
-        match self.get_header("id") {
-            Some(h) => Some(Id(h.get_value())),
-            None => None,
-        }
+impl<'a> TypedHeader<'a> for Version {
+    const NAME: &'static str = "version";
+
+    fn parse(value: &'a str) -> Option<Version> {
+        value.parse::<StompVersion>().ok().map(Version)
     }
+}
 
-    pub fn get_login(&self) -> Option<Login> {
-        match self.get_header("login") {
-            Some(h) => Some(Login(h.get_value())),
-            None => None,
-        }
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
     }
+}
 
-    pub fn get_message_id(&self) -> Option<MessageId> {
-        match self.get_header("message-id") {
-            Some(h) => Some(MessageId(h.get_value())),
-            None => None,
-        }
+impl HeaderList {
+    pub fn get_header(&self, key: &str) -> Option<&Header> {
+        self.headers.iter().find(|header| header.get_key() == key)
     }
 
-    pub fn get_passcode(&self) -> Option<Passcode> {
-        match self.get_header("passcode") {
-            Some(h) => Some(Passcode(h.get_value())),
-            None => None,
-        }
+    /// Parses the first header named `H::NAME`, if present and valid.
+    pub fn typed<'a, H: TypedHeader<'a>>(&'a self) -> Option<H> {
+        self.get_header(H::NAME).and_then(|h| H::parse(h.get_value()))
     }
 
-    pub fn get_receipt(&self) -> Option<Receipt> {
-        match self.get_header("receipt") {
-            Some(h) => Some(Receipt(h.get_value())),
-            None => None,
+    /// Replaces any headers named `H::NAME` with `header`, escaped unless
+    /// the headers were received.
+    pub fn set_typed<'a, H: TypedHeader<'a>>(&mut self, header: H) {
+        let value = header.encode();
+        if self.decoded {
+            self.insert(Header::new_raw(H::NAME, value));
+        } else {
+            self.insert(Header::new(H::NAME, &value));
         }
     }
 
-    pub fn get_receipt_id(&self) -> Option<ReceiptId> {
-        match self.get_header("receipt-id") {
-            Some(h) => Some(ReceiptId(h.get_value())),
-            None => None,
-        }
+    /// A list holding only `header`.
+    pub fn from_typed<'a, H: TypedHeader<'a>>(header: H) -> HeaderList {
+        let mut headers = HeaderList::with_capacity(1);
+        headers.set_typed(header);
+        headers
     }
 
-    pub fn get_server(&self) -> Option<Server> {
-        match self.get_header("server") {
-            Some(h) => Some(Server(h.get_value())),
-            None => None,
-        }
+    pub fn get_accept_version(&self) -> Option<Vec<StompVersion>> {
+        self.typed::<AcceptVersion>().map(|AcceptVersion(versions)| versions)
     }
 
-    pub fn get_session(&self) -> Option<Session> {
-        match self.get_header("session") {
-            Some(h) => Some(Session(h.get_value())),
-            None => None,
-        }
+    pub fn get_ack(&self) -> Option<Ack<'_>> {
+        self.typed()
     }
 
-    pub fn get_subscription(&self) -> Option<Subscription> {
-        match self.get_header("subscription") {
-            Some(h) => Some(Subscription(h.get_value())),
-            None => None,
-        }
+    pub fn get_destination(&self) -> Option<Destination<'_>> {
+        self.typed()
     }
 
-    pub fn get_transaction(&self) -> Option<Transaction> {
-        match self.get_header("transaction") {
-            Some(h) => Some(Transaction(h.get_value())),
-            None => None,
-        }
+    pub fn get_heart_beat(&self) -> Option<HeartBeat> {
+        self.typed()
+    }
+
+    pub fn get_host(&self) -> Option<Host<'_>> {
+        self.typed()
+    }
+
+    pub fn get_id(&self) -> Option<Id<'_>> {
+        self.typed()
+    }
+
+    pub fn get_login(&self) -> Option<Login<'_>> {
+        self.typed()
+    }
+
+    pub fn get_message(&self) -> Option<Message<'_>> {
+        self.typed()
+    }
+
+    pub fn get_message_id(&self) -> Option<MessageId<'_>> {
+        self.typed()
+    }
+
+    pub fn get_passcode(&self) -> Option<Passcode<'_>> {
+        self.typed()
+    }
+
+    pub fn get_receipt(&self) -> Option<Receipt<'_>> {
+        self.typed()
+    }
+
+    pub fn get_receipt_id(&self) -> Option<ReceiptId<'_>> {
+        self.typed()
+    }
+
+    pub fn get_server(&self) -> Option<Server<'_>> {
+        self.typed()
+    }
+
+    pub fn get_session(&self) -> Option<Session<'_>> {
+        self.typed()
+    }
+
+    pub fn get_subscription(&self) -> Option<Subscription<'_>> {
+        self.typed()
+    }
+
+    pub fn get_transaction(&self) -> Option<Transaction<'_>> {
+        self.typed()
     }
 
     pub fn get_version(&self) -> Option<Version> {
-        let version = match self.get_header("version") {
-            Some(h) => h.get_value(),
-            None => return None,
-        };
-        match version {
-            "1.0" => Some(Version(StompVersion::Stomp_v1_0)), // TODO: Impl FromStr for StompVersion
-            "1.1" => Some(Version(StompVersion::Stomp_v1_1)),
-            "1.2" => Some(Version(StompVersion::Stomp_v1_2)),
-            _ => None,
-        }
+        self.typed()
     }
 
     pub fn get_content_length(&self) -> Option<ContentLength> {
-        let length = match self.get_header("content-length") {
-            Some(h) => h.get_value(),
-            None => return None,
-        };
-        match length.parse::<u32>().ok() {
-            Some(l) => Some(ContentLength(l)),
-            None => None,
-        }
+        self.typed()
+    }
+
+    pub fn get_content_type(&self) -> Option<ContentType<'_>> {
+        self.typed()
+    }
+
+    pub fn get_client_id(&self) -> Option<ClientId<'_>> {
+        self.typed()
+    }
+
+    pub fn get_correlation_id(&self) -> Option<CorrelationId<'_>> {
+        self.typed()
+    }
+
+    pub fn get_reply_to(&self) -> Option<ReplyTo<'_>> {
+        self.typed()
     }
 }
 
@@ -331,3 +613,96 @@ fn encode_slash() {
     let encoded = r"Hello\\World";
     assert!(encoded == Header::encode_value(unencoded));
 }
+
+#[test]
+fn typed_spec_headers() {
+    let mut headers = HeaderList::new();
+    headers.push(Header::new("heart-beat", "1000, 2000"));
+    headers.push(Header::new("version", "1.1"));
+    headers.push(Header::new("content-length", "not a number"));
+    match headers.typed::<HeartBeat>() {
+        Some(HeartBeat(1000, 2000)) => {}
+        _ => panic!("heart-beat not parsed"),
+    }
+    assert_eq!(headers.get_version().map(|Version(v)| v), Some(StompVersion::Stomp_v1_1));
+    assert!(headers.get_content_length().is_none());
+
+    headers.set_typed(ContentLength(12));
+    headers.set_typed(AcceptVersion(vec![StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2]));
+    assert_eq!(headers.get_header("content-length").unwrap().get_value(), "12");
+    assert_eq!(headers.get_header("accept-version").unwrap().get_value(), "1.1,1.2");
+    assert_eq!(headers.iter().filter(|h| h.get_key() == "content-length").count(), 1);
+}
+
+#[test]
+fn parse_stomp_version() {
+    assert_eq!("1.2".parse::<StompVersion>(), Ok(StompVersion::Stomp_v1_2));
+    assert!("2.0".parse::<StompVersion>().is_err());
+    assert_eq!(StompVersion::Stomp_v1_0.to_string(), "1.0");
+}
+
+#[test]
+fn header_list_map_operations() {
+    let mut headers = header_list![
+        "destination" => "/queue/a",
+        "ack" => "auto",
+        "id" => "1",
+        "ack" => "client"
+    ];
+    assert!(headers.contains("ack"));
+    assert_eq!(headers.get_all("ack").map(|h| h.get_value()).collect::<Vec<_>>(), vec!["auto", "client"]);
+
+    let replaced = headers.insert(Header::new("ack", "client-individual"));
+    assert_eq!(replaced.map(|h| h.1), Some("auto".to_owned()));
+    let keys: Vec<&str> = headers.iter().map(|h| h.get_key()).collect();
+    assert_eq!(keys, vec!["destination", "ack", "id"]);
+    assert_eq!(headers.get_ack().map(|Ack(ack)| ack), Some("client-individual"));
+
+    headers.append(Header::new("id", "2"));
+    assert_eq!(headers.remove("id").map(|h| h.1), Some("1".to_owned()));
+    assert!(!headers.contains("id"));
+    assert!(headers.remove("id").is_none());
+}
+
+#[test]
+fn duplicate_header_policies() {
+    let headers = header_list![
+        "ack" => "auto",
+        "id" => "1",
+        "ack" => "client"
+    ];
+    let values = |headers: &HeaderList| -> Vec<String> { headers.iter().map(|h| h.get_raw()).collect() };
+
+    let mut first = headers.clone();
+    first.apply_policy(DuplicateHeaderPolicy::KeepFirst).unwrap();
+    assert_eq!(values(&first), vec!["ack:auto", "id:1"]);
+
+    let mut last = headers.clone();
+    last.apply_policy(DuplicateHeaderPolicy::KeepLast).unwrap();
+    assert_eq!(values(&last), vec!["id:1", "ack:client"]);
+
+    let mut rejected = headers.clone();
+    assert_eq!(rejected.apply_policy(DuplicateHeaderPolicy::Reject), Err("ack".to_owned()));
+
+    let mut allowed = headers.clone();
+    allowed.apply_policy(DuplicateHeaderPolicy::Allow).unwrap();
+    assert_eq!(values(&allowed).len(), 3);
+}
+
+#[test]
+fn escape_built_and_received_headers() {
+    let mut built = HeaderList::new();
+    built.push(Header::new("key", "a:b"));
+    built.push(Header::new_raw("raw", "a\\tb"));
+    built.set_typed(Destination("/queue/a:b"));
+    let raw: Vec<String> = built.iter().map(|h| built.escaped(h).get_raw()).collect();
+    assert_eq!(raw, vec!["key:a\\cb", "raw:a\\tb", "destination:/queue/a\\cb"]);
+    assert_eq!(built.get_header("key").unwrap().get_value(), "a\\cb");
+
+    let mut received = HeaderList::decoded();
+    received.push(Header("key".to_owned(), "a:b".to_owned()));
+    received.set_typed(Destination("/queue/a:b"));
+    let raw: Vec<String> = received.iter().map(|h| received.escaped(h).get_raw()).collect();
+    assert_eq!(raw, vec!["key:a\\cb", "destination:/queue/a\\cb"]);
+    assert_eq!(received.get_destination().map(|d| d.0), Some("/queue/a:b"));
+}
@@ -0,0 +1,99 @@
+//! Hooks which inspect, modify or drop frames as they are sent and received,
+//! e.g. to work around broker quirks not covered by the API.
+use crate::frame::Frame;
+use std::rc::Rc;
+
+/// A hook registered on a `SessionBuilder`. Interceptors run in the order
+/// they were added.
+pub trait Interceptor {
+    /// Called for every frame before it is sent. Returning `false` drops the
+    /// frame.
+    fn before_send(&self, _frame: &mut Frame) -> bool {
+        true
+    }
+
+    /// Called for every frame received, before the `Session` acts on it.
+    /// Returning `false` drops the frame. The frame of a streamed message
+    /// has an empty body.
+    fn before_receive(&self, _frame: &mut Frame) -> bool {
+        true
+    }
+}
+
+/// An interceptor calling a closure before frames are sent.
+pub struct BeforeSend<F>(pub F);
+
+impl<F> Interceptor for BeforeSend<F>
+where
+    F: Fn(&mut Frame) -> bool,
+{
+    fn before_send(&self, frame: &mut Frame) -> bool {
+        (self.0)(frame)
+    }
+}
+
+/// An interceptor calling a closure before received frames are handled.
+pub struct BeforeReceive<F>(pub F);
+
+impl<F> Interceptor for BeforeReceive<F>
+where
+    F: Fn(&mut Frame) -> bool,
+{
+    fn before_receive(&self, frame: &mut Frame) -> bool {
+        (self.0)(frame)
+    }
+}
+
+pub(crate) type Interceptors = Vec<Rc<dyn Interceptor>>;
+
+// Runs each interceptor's `before_send`, stopping at the first which drops
+// the frame.
+pub(crate) fn before_send(interceptors: &[Rc<dyn Interceptor>], frame: &mut Frame) -> bool {
+    interceptors
+        .iter()
+        .all(|interceptor| interceptor.before_send(frame))
+}
+
+pub(crate) fn before_receive(interceptors: &[Rc<dyn Interceptor>], frame: &mut Frame) -> bool {
+    interceptors
+        .iter()
+        .all(|interceptor| interceptor.before_receive(frame))
+}
+
+#[test]
+fn interceptor_chain() {
+    use crate::frame::Command;
+    use crate::header::Header;
+
+    let interceptors: Interceptors = vec![
+        Rc::new(BeforeSend(|frame: &mut Frame| {
+            if let Command::Nack = frame.command {
+                frame.headers.push(Header::new("requeue", "false"));
+            }
+            true
+        })),
+        Rc::new(BeforeSend(|frame: &mut Frame| {
+            !frame.headers.contains("drop")
+        })),
+        Rc::new(BeforeReceive(|frame: &mut Frame| {
+            frame.headers.remove("x-broker-internal");
+            true
+        })),
+    ];
+
+    let mut nack = Frame::nack("ack/1");
+    assert!(before_send(&interceptors, &mut nack));
+    assert_eq!(
+        nack.headers.get_header("requeue").unwrap().get_value(),
+        "false"
+    );
+
+    let mut dropped = Frame::send("/queue/a", b"");
+    dropped.headers.push(Header::new("drop", "true"));
+    assert!(!before_send(&interceptors, &mut dropped));
+
+    let mut received = Frame::send("/queue/a", b"");
+    received.headers.push(Header::new("x-broker-internal", "1"));
+    assert!(before_receive(&interceptors, &mut received));
+    assert!(!received.headers.contains("x-broker-internal"));
+}
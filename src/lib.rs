@@ -9,19 +9,37 @@ extern crate bytes;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_io;
-extern crate unicode_segmentation;
-#[macro_use]
 extern crate nom;
 
+pub mod body;
 pub mod codec;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub mod compression;
 pub mod connection;
+pub mod consumer;
+pub mod destination;
+pub mod dialect;
+pub mod escape;
 #[rustfmt::skip]
 pub mod header; // this must come before frame, because it defines header_list!
 pub mod frame;
+pub mod dedup;
+pub mod interceptor;
+pub mod error;
+pub mod media_type;
 pub mod message_builder;
 pub mod option_setter;
+#[cfg(feature = "serde")]
+pub mod payload;
+pub mod publish;
+pub mod redelivery;
+pub mod request;
+pub mod selector;
 pub mod session;
 pub mod session_builder;
+pub mod spool;
 pub mod subscription;
 pub mod subscription_builder;
 pub mod transaction;
+#[cfg(test)]
+mod test_broker;
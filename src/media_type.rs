@@ -0,0 +1,184 @@
+//! Parsing of `content-type` header values such as
+//! `text/plain;charset=ISO-8859-1`.
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A MIME type with its parameters. The type, subtype and parameter names
+/// are case-insensitive and kept in lowercase.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaType {
+    essence: String,
+    slash: usize,
+    params: Vec<(String, String)>,
+}
+
+/// A `content-type` value which is not a valid MIME type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidMediaType(pub String);
+
+impl fmt::Display for InvalidMediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid media type '{}'", self.0)
+    }
+}
+
+impl error::Error for InvalidMediaType {}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+impl MediaType {
+    /// The type, e.g. `text` for `text/plain`.
+    pub fn type_(&self) -> &str {
+        &self.essence[..self.slash]
+    }
+
+    /// The subtype, e.g. `plain` for `text/plain`.
+    pub fn subtype(&self) -> &str {
+        &self.essence[self.slash + 1..]
+    }
+
+    /// The type and subtype without parameters, e.g. `text/plain`.
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    /// The value of the parameter `name`, compared case-insensitively.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The `charset` parameter.
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// Whether bodies of this type are text: `text/*`, and the JSON and XML
+    /// based types.
+    pub fn is_text(&self) -> bool {
+        let subtype = self.subtype();
+        self.type_() == "text"
+            || subtype == "json"
+            || subtype == "xml"
+            || subtype.ends_with("+json")
+            || subtype.ends_with("+xml")
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = InvalidMediaType;
+
+    fn from_str(value: &str) -> Result<MediaType, InvalidMediaType> {
+        let invalid = || InvalidMediaType(value.to_owned());
+        let mut rest = value;
+        let essence_end = rest.find(';').unwrap_or(rest.len());
+        let essence = rest[..essence_end].trim().to_ascii_lowercase();
+        rest = &rest[essence_end..];
+        let slash = essence.find('/').ok_or_else(invalid)?;
+        if !is_token(&essence[..slash]) || !is_token(&essence[slash + 1..]) {
+            return Err(invalid());
+        }
+
+        let mut params = Vec::new();
+        while let Some(after_semicolon) = rest.strip_prefix(';') {
+            rest = after_semicolon.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            let equals = rest.find('=').ok_or_else(invalid)?;
+            let name = rest[..equals].trim().to_ascii_lowercase();
+            if !is_token(&name) {
+                return Err(invalid());
+            }
+            rest = &rest[equals + 1..];
+            let param_value = if let Some(quoted) = rest.strip_prefix('"') {
+                let mut unquoted = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => break i,
+                        Some((_, '\\')) => unquoted.extend(chars.next().map(|(_, c)| c)),
+                        Some((_, c)) => unquoted.push(c),
+                        None => return Err(invalid()),
+                    }
+                };
+                rest = quoted[end + 1..].trim_start();
+                unquoted
+            } else {
+                let end = rest.find(';').unwrap_or(rest.len());
+                let token = rest[..end].trim();
+                rest = &rest[end..];
+                token.to_owned()
+            };
+            if !rest.is_empty() && !rest.starts_with(';') {
+                return Err(invalid());
+            }
+            params.push((name, param_value));
+        }
+
+        Ok(MediaType {
+            essence,
+            slash,
+            params,
+        })
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.essence)?;
+        for (name, value) in &self.params {
+            if is_token(value) {
+                write!(f, ";{}={}", name, value)?;
+            } else {
+                write!(
+                    f,
+                    ";{}=\"{}\"",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_media_types() {
+    let media_type: MediaType = "Text/Plain; Charset=ISO-8859-1".parse().unwrap();
+    assert_eq!(media_type.essence(), "text/plain");
+    assert_eq!(media_type.type_(), "text");
+    assert_eq!(media_type.subtype(), "plain");
+    assert_eq!(media_type.charset(), Some("ISO-8859-1"));
+    assert!(media_type.is_text());
+    assert_eq!(media_type.to_string(), "text/plain;charset=ISO-8859-1");
+
+    let quoted: MediaType = r#"application/vnd.x+json;profile="a \"b\"; c";v=1"#
+        .parse()
+        .unwrap();
+    assert_eq!(quoted.param("profile"), Some(r#"a "b"; c"#));
+    assert_eq!(quoted.param("v"), Some("1"));
+    assert!(quoted.is_text());
+    assert_eq!(quoted.to_string().parse::<MediaType>().unwrap(), quoted);
+
+    assert!("application/octet-stream"
+        .parse::<MediaType>()
+        .map(|m| !m.is_text())
+        .unwrap());
+    assert!("plain".parse::<MediaType>().is_err());
+    assert!("text/plain;charset".parse::<MediaType>().is_err());
+    assert!("text/plain;charset=\"utf-8".parse::<MediaType>().is_err());
+}
@@ -1,11 +1,13 @@
 use crate::frame::Frame;
 use crate::option_setter::OptionSetter;
-use crate::session::{OutstandingReceipt, ReceiptRequest, Session};
+use crate::session::{GenerateReceipt, OutstandingReceipt, ReceiptRequest, Session};
+use tokio_io::AsyncRead;
 
 pub struct MessageBuilder<'a> {
     pub session: &'a mut Session,
     pub frame: Frame,
     pub receipt_request: Option<ReceiptRequest>,
+    pub(crate) body_reader: Option<(Box<dyn AsyncRead>, u64)>,
 }
 
 impl<'a> MessageBuilder<'a> {
@@ -14,19 +16,43 @@ impl<'a> MessageBuilder<'a> {
             session,
             frame,
             receipt_request: None,
+            body_reader: None,
         }
     }
 
     #[allow(dead_code)]
-    pub fn send(self) {
-        if self.receipt_request.is_some() {
-            let request = self.receipt_request.unwrap();
+    pub fn send(mut self) {
+        let deadline = match self.body_reader {
+            Some(_) => None,
+            None => self.session.reliable_deadline(&self.frame),
+        };
+        if deadline.is_some() && self.receipt_request.is_none() {
+            self = GenerateReceipt.set_option(self);
+        }
+        if let Some(request) = self.receipt_request {
+            if let Some(deadline) = deadline {
+                self.session
+                    .state
+                    .unconfirmed
+                    .push(request.id.clone(), deadline);
+            }
+            let mut original_frame = self.frame.clone();
+            if self.body_reader.is_some() {
+                // The streamed body is not kept, so the copy must not claim it
+                original_frame.headers.remove("content-length");
+            }
             self.session
                 .state
                 .outstanding_receipts
-                .insert(request.id, OutstandingReceipt::new(self.frame.clone()));
+                .insert(request.id, OutstandingReceipt::new(original_frame));
+        }
+        match self.body_reader {
+            Some((reader, content_length)) => {
+                self.session
+                    .send_streamed(self.frame, reader, content_length)
+            }
+            None => self.session.send_frame(self.frame),
         }
-        self.session.send_frame(self.frame)
     }
 
     #[allow(dead_code)]
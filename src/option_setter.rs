@@ -1,10 +1,27 @@
+use crate::body::StreamLargeBodies;
+use crate::codec::FrameLimits;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::compression::Compress;
 use crate::connection::{Credentials, HeartBeat, OwnedCredentials};
-use crate::header::{ContentType, Header, SuppressedHeader};
+use crate::dedup::Deduplicate;
+use crate::dialect::{activemq, artemis, rabbitmq, Expires, Persistent, Priority, Selector};
+use crate::escape::DecodeMode;
+use crate::frame::Frame;
+use crate::header::{
+    ClientId, ContentType, DuplicateHeaderPolicy, Header, HeaderList, SuppressedHeader,
+};
+use crate::interceptor::{BeforeReceive, BeforeSend, Interceptor};
 use crate::message_builder::MessageBuilder;
+use crate::publish::ReliablePublish;
+use crate::redelivery::RedeliveryPolicy;
+use crate::request::ReplyQueue;
+use crate::selector::Filter;
 use crate::session::{GenerateReceipt, ReceiptRequest};
 use crate::session_builder::SessionBuilder;
-use crate::subscription::AckMode;
+use crate::spool::Spool;
+use crate::subscription::{AckMode, SubscriptionId};
 use crate::subscription_builder::SubscriptionBuilder;
+use std::rc::Rc;
 
 pub trait OptionSetter<T> {
     fn set_option(self, _: T) -> T;
@@ -53,6 +70,83 @@ impl OptionSetter<SessionBuilder> for HeartBeat {
     }
 }
 
+impl OptionSetter<SessionBuilder> for FrameLimits {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.limits = self;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for ReplyQueue {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.reply_queue = Some(self);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for ReliablePublish {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.reliable_publish = Some(self);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for Spool {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.spool = Some(self);
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for Rc<dyn Interceptor> {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.interceptors.push(self);
+        builder
+    }
+}
+
+impl<F> OptionSetter<SessionBuilder> for BeforeSend<F>
+where
+    F: Fn(&mut Frame) -> bool + 'static,
+{
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.interceptors.push(Rc::new(self));
+        builder
+    }
+}
+
+impl<F> OptionSetter<SessionBuilder> for BeforeReceive<F>
+where
+    F: Fn(&mut Frame) -> bool + 'static,
+{
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.interceptors.push(Rc::new(self));
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for DecodeMode {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.header_decoding = self;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for DuplicateHeaderPolicy {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.header_policy = self;
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for StreamLargeBodies {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        let StreamLargeBodies(threshold) = self;
+        builder.config.stream_threshold = Some(threshold);
+        builder
+    }
+}
+
 impl<'b> OptionSetter<SessionBuilder> for Credentials<'b> {
     fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
         builder.config.credentials = Some(OwnedCredentials::from(self));
@@ -93,6 +187,18 @@ impl<'a> OptionSetter<SubscriptionBuilder<'a>> for AckMode {
     }
 }
 
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+impl<'a> OptionSetter<MessageBuilder<'a>> for Compress {
+    fn set_option(self, mut builder: MessageBuilder<'a>) -> MessageBuilder<'a> {
+        if builder.body_reader.is_some() {
+            warn!("Sending message uncompressed, streamed bodies cannot be compressed");
+            return builder;
+        }
+        self.apply(&mut builder.frame);
+        builder
+    }
+}
+
 impl<'a> OptionSetter<MessageBuilder<'a>> for GenerateReceipt {
     fn set_option(self, mut builder: MessageBuilder<'a>) -> MessageBuilder<'a> {
         let next_id = builder.session.generate_receipt_id();
@@ -117,3 +223,109 @@ impl<'a> OptionSetter<SubscriptionBuilder<'a>> for GenerateReceipt {
         builder
     }
 }
+
+// Typed headers which set their value on the frame, replacing any previous one
+macro_rules! message_headers {
+    ($($header: ty), *) => {
+        $(
+            impl<'a> OptionSetter<MessageBuilder<'a>> for $header {
+                fn set_option(self, mut builder: MessageBuilder<'a>) -> MessageBuilder<'a> {
+                    builder.frame.headers.set_typed(self);
+                    builder
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! subscription_headers {
+    ($($header: ty), *) => {
+        $(
+            impl<'a> OptionSetter<SubscriptionBuilder<'a>> for $header {
+                fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+                    builder.headers.set_typed(self);
+                    builder
+                }
+            }
+        )*
+    }
+}
+
+message_headers!(
+    Persistent,
+    Priority,
+    Expires,
+    artemis::RoutingType,
+    rabbitmq::Expires
+);
+
+subscription_headers!(
+    Selector<'_>,
+    activemq::PrefetchCount,
+    artemis::ConsumerWindowSize,
+    artemis::RoutingType,
+    rabbitmq::PrefetchCount,
+    rabbitmq::XQueueType
+);
+
+impl<'a> OptionSetter<SubscriptionBuilder<'a>> for Filter {
+    fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+        builder.headers.set_typed(Selector(self.selector()));
+        builder.filter = Some(self);
+        builder
+    }
+}
+
+// Options which make a subscription durable, and remember how to destroy it.
+// `$headers` gives the headers of both the SUBSCRIBE frame and the UNSUBSCRIBE
+// frame which destroys the subscription.
+macro_rules! durable_subscriptions {
+    ($($option: ty => $headers: expr), *) => {
+        $(
+            impl<'a> OptionSetter<SubscriptionBuilder<'a>> for $option {
+                fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+                    let durable = $headers(self);
+                    for header in durable.iter() {
+                        builder.headers.insert(header.clone());
+                    }
+                    builder.durable = Some(durable);
+                    builder
+                }
+            }
+        )*
+    }
+}
+
+durable_subscriptions!(
+    activemq::DurableSubscriptionName<'_> => HeaderList::from_typed,
+    artemis::DurableSubscriptionName<'_> => HeaderList::from_typed,
+    rabbitmq::DurableSubscriptionName<'_> => rabbitmq::DurableSubscriptionName::headers
+);
+
+impl<'a> OptionSetter<SubscriptionBuilder<'a>> for RedeliveryPolicy {
+    fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+        builder.redelivery = Some(self);
+        builder
+    }
+}
+
+impl<'a> OptionSetter<SubscriptionBuilder<'a>> for Deduplicate {
+    fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+        builder.dedup = Some(self);
+        builder
+    }
+}
+
+impl<'a> OptionSetter<SubscriptionBuilder<'a>> for SubscriptionId<'_> {
+    fn set_option(self, mut builder: SubscriptionBuilder<'a>) -> SubscriptionBuilder<'a> {
+        builder.id = Some(self.0.to_owned());
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for ClientId<'_> {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.headers.set_typed(self);
+        builder
+    }
+}
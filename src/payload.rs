@@ -0,0 +1,219 @@
+//! Message bodies serialized with serde. JSON is available with the `serde`
+//! feature, CBOR and MessagePack with the `cbor` and `msgpack` features.
+//! Other formats can be plugged in by implementing `PayloadCodec`.
+use crate::destination::Destination;
+use crate::error::{Error, Result};
+use crate::frame::Frame;
+use crate::header::ContentType;
+use crate::message_builder::MessageBuilder;
+use crate::session::Session;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Codecs receive the values to encode as `erased_serde::Serialize` trait
+/// objects, which `serde::Serialize` is implemented for.
+pub use erased_serde;
+
+/// A serialization format for message bodies. `Session::message_payload`
+/// takes codecs as trait objects, so `decode` is only available on concrete
+/// codecs.
+pub trait PayloadCodec {
+    /// The `content-type` of the bodies produced by `encode`.
+    fn content_type(&self) -> &str;
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T>
+    where
+        Self: Sized;
+}
+
+pub struct Json;
+
+impl PayloadCodec for Json {
+    fn content_type(&self) -> &str {
+        "application/json;charset=utf-8"
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::Payload(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+        serde_json::from_slice(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl PayloadCodec for Cbor {
+    fn content_type(&self) -> &str {
+        "application/cbor"
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        ciborium::into_writer(value, &mut body).map_err(|e| Error::Payload(e.into()))?;
+        Ok(body)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+        ciborium::from_reader(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl PayloadCodec for MessagePack {
+    fn content_type(&self) -> &str {
+        "application/msgpack"
+    }
+
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::Payload(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(body).map_err(|e| Error::Payload(e.into()))
+    }
+}
+
+impl Session {
+    /// Prepares a message whose body is `value` serialized as JSON.
+    pub fn message_json<D: Into<Destination>, T: Serialize>(
+        &mut self,
+        destination: D,
+        value: &T,
+    ) -> Result<MessageBuilder<'_>> {
+        self.message_payload(destination, &Json, value)
+    }
+
+    /// Prepares a message whose body is `value` serialized with `codec`, and
+    /// whose `content-type` is the codec's.
+    pub fn message_payload<D: Into<Destination>, T: Serialize>(
+        &mut self,
+        destination: D,
+        codec: &dyn PayloadCodec,
+        value: &T,
+    ) -> Result<MessageBuilder<'_>> {
+        let body = codec.encode(value)?;
+        let content_type = codec.content_type().to_owned();
+        Ok(self
+            .message(destination, &body[..])
+            .with(ContentType(&content_type)))
+    }
+}
+
+impl Frame {
+    /// Deserializes the body as JSON, whatever the `content-type`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Json.decode(&self.body)
+    }
+
+    /// Deserializes the body with the codec matching the `content-type`.
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T> {
+        let media_type = match self.headers.get_content_type() {
+            Some(content_type) => content_type
+                .media_type()
+                .map_err(|e| Error::Payload(e.into()))?,
+            None => return Err(Error::Payload("message has no content-type".into())),
+        };
+        match media_type.essence() {
+            "application/json" => Json.decode(&self.body),
+            essence if essence.ends_with("+json") => Json.decode(&self.body),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Cbor.decode(&self.body),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                MessagePack.decode(&self.body)
+            }
+            _ => Err(Error::Payload(
+                format!("no payload codec for content-type '{}'", media_type).into(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Order {
+    id: u32,
+    items: Vec<String>,
+}
+
+#[cfg(test)]
+fn encoded_frame(codec: &dyn PayloadCodec, order: &Order) -> Frame {
+    use crate::header::Header;
+
+    let mut frame = Frame::send("/queue/orders", &codec.encode(order).unwrap());
+    frame
+        .headers
+        .push(Header::new("content-type", codec.content_type()));
+    frame
+}
+
+#[test]
+fn json_payload_roundtrip() {
+    let order = Order {
+        id: 7,
+        items: vec!["tea".to_owned()],
+    };
+    let frame = encoded_frame(&Json, &order);
+    assert_eq!(frame.body, br#"{"id":7,"items":["tea"]}"#.to_vec());
+    assert_eq!(frame.json::<Order>().unwrap(), order);
+    assert_eq!(frame.payload::<Order>().unwrap(), order);
+}
+
+#[cfg(all(feature = "cbor", feature = "msgpack"))]
+#[test]
+fn payload_codec_from_content_type() {
+    let order = Order {
+        id: 7,
+        items: vec!["tea".to_owned()],
+    };
+    assert_eq!(
+        encoded_frame(&Cbor, &order).payload::<Order>().unwrap(),
+        order
+    );
+    assert_eq!(
+        encoded_frame(&MessagePack, &order)
+            .payload::<Order>()
+            .unwrap(),
+        order
+    );
+
+    let text = Frame::send("/queue/orders", b"7");
+    assert!(text.payload::<Order>().is_err());
+}
+
+#[test]
+fn custom_payload_codec() {
+    // JSON under a vendor content-type
+    struct Vendor;
+
+    impl PayloadCodec for Vendor {
+        fn content_type(&self) -> &str {
+            "application/vnd.orders+json"
+        }
+
+        fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+            Json.encode(value)
+        }
+
+        fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+            Json.decode(body)
+        }
+    }
+
+    let order = Order {
+        id: 7,
+        items: vec!["tea".to_owned()],
+    };
+    let frame = encoded_frame(&Vendor, &order);
+    assert_eq!(frame.payload::<Order>().unwrap(), order);
+    assert_eq!(Vendor.decode::<Order>(&frame.body).unwrap(), order);
+}
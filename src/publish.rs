@@ -0,0 +1,204 @@
+//! At-least-once publishing. With `ReliablePublish`, every message requests a
+//! receipt and is kept until the broker confirms it. Messages still
+//! unconfirmed when the connection is lost are sent again once the session
+//! has reconnected, so the broker may receive some of them twice. Together
+//! with a `Spool`, they are sent again before the spooled messages, which
+//! were sent after them, so that the broker receives messages in the order
+//! they were sent.
+use futures::task;
+use futures::{Async, Future};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Sends messages reliably, giving up on those which are not confirmed
+/// within the deadline with a `SessionEvent::SendFailed`. Messages sent in a
+/// transaction, or with a streamed body, are not covered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReliablePublish(pub Duration);
+
+// The receipt ids of the messages waiting for confirmation, in the order
+// they were sent, with their deadlines
+#[derive(Default)]
+pub(crate) struct Unconfirmed {
+    sent: VecDeque<(String, Instant)>,
+    timer: Option<Timeout>,
+}
+
+impl Unconfirmed {
+    pub fn push(&mut self, receipt_id: String, deadline: Instant) {
+        self.sent.push_back((receipt_id, deadline));
+    }
+
+    // The receipt ids for which `pending` holds, in the order they were sent
+    pub fn pending<'a, F>(&'a self, pending: F) -> impl Iterator<Item = &'a str>
+    where
+        F: Fn(&str) -> bool + 'a,
+    {
+        self.sent
+            .iter()
+            .map(|(receipt_id, _)| receipt_id.as_str())
+            .filter(move |receipt_id| pending(receipt_id))
+    }
+
+    // Forgets the messages which are no longer pending, returning the receipt
+    // ids of the pending ones whose deadline has passed
+    pub fn expire<F>(&mut self, now: Instant, pending: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut expired = Vec::new();
+        self.sent.retain(|(receipt_id, deadline)| {
+            if !pending(receipt_id) {
+                return false;
+            }
+            if *deadline <= now {
+                expired.push(receipt_id.clone());
+                return false;
+            }
+            true
+        });
+        expired
+    }
+
+    // Wakes the current task when the earliest deadline passes
+    pub fn schedule(&mut self, hdl: &Handle) {
+        let deadline = match self.sent.front() {
+            Some((_, deadline)) => *deadline,
+            None => {
+                self.timer = None;
+                return;
+            }
+        };
+        match self.timer {
+            Some(ref mut timer) => timer.reset(deadline),
+            None => match Timeout::new_at(deadline, hdl) {
+                Ok(timer) => self.timer = Some(timer),
+                Err(e) => warn!("Could not schedule the deadline of a message: {}", e),
+            },
+        }
+        if let Some(ref mut timer) = self.timer {
+            if let Ok(Async::NotReady) = timer.poll() {
+                return;
+            }
+            task::current().notify();
+        }
+    }
+}
+
+#[test]
+fn expire_unconfirmed_messages() {
+    let now = Instant::now();
+    let mut unconfirmed = Unconfirmed::default();
+    unconfirmed.push("message/0".to_owned(), now);
+    unconfirmed.push("message/1".to_owned(), now + Duration::from_secs(1));
+    unconfirmed.push("message/2".to_owned(), now + Duration::from_secs(2));
+
+    // message/1 has been confirmed
+    let pending = |receipt_id: &str| receipt_id != "message/1";
+    assert_eq!(
+        unconfirmed.pending(pending).collect::<Vec<_>>(),
+        vec!["message/0", "message/2"]
+    );
+    assert_eq!(unconfirmed.expire(now, pending), vec!["message/0"]);
+    assert_eq!(
+        unconfirmed.pending(|_| true).collect::<Vec<_>>(),
+        vec!["message/2"]
+    );
+    assert!(unconfirmed
+        .expire(now + Duration::from_secs(1), |_| true)
+        .is_empty());
+    assert_eq!(
+        unconfirmed.expire(now + Duration::from_secs(2), |_| true),
+        vec!["message/2"]
+    );
+}
+
+#[test]
+fn resend_unconfirmed_messages_after_reconnecting() {
+    use crate::frame::Frame;
+    use crate::session::SessionEvent;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, connected, header_value, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let sent: Vec<Frame> = (0..2).map(|_| client.read()).collect();
+        client.receipt(&sent[0]);
+        // The connection is lost before the second message is confirmed
+        drop(client);
+        let mut client = Client::connect(&listener);
+        let resent = client.read();
+        assert_eq!(resent.body, b"two");
+        assert_eq!(
+            header_value(&resent, "receipt"),
+            header_value(&sent[1], "receipt")
+        );
+        client.receipt(&resent);
+        client.quiet(Duration::from_millis(100));
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReliablePublish(Duration::from_secs(10)))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+    session.message("/queue/a", "one").send();
+    session.message("/queue/a", "two").send();
+    assert_eq!(session.unconfirmed(), 2);
+    run_until(&mut core, &mut session, |_, events| {
+        events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Disconnected(_)))
+    });
+    assert_eq!(session.unconfirmed(), 1);
+    run_until(&mut core, &mut session, |session, _| {
+        session.reconnect().unwrap();
+        true
+    });
+    run_until(&mut core, &mut session, |session, events| {
+        events.iter().any(connected) && session.unconfirmed() == 0
+    });
+    broker.join().unwrap();
+}
+
+#[test]
+fn fail_messages_unconfirmed_at_the_deadline() {
+    use crate::error::Error;
+    use crate::session::SessionEvent;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, connected, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let sent = client.read();
+        assert_eq!(sent.body, b"one");
+        // No receipt is sent, nor anything else
+        client.quiet(Duration::from_millis(500));
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReliablePublish(Duration::from_millis(100)))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+    let sent = Instant::now();
+    session.message("/queue/a", "one").send();
+    let events = run_until(&mut core, &mut session, |_, events| !events.is_empty());
+    match events.as_slice() {
+        [SessionEvent::SendFailed {
+            frame,
+            error: Error::ReceiptTimeout(_),
+        }] => assert_eq!(frame.body, b"one"),
+        _ => panic!("expected the message to fail"),
+    }
+    assert!(sent.elapsed() >= Duration::from_millis(100));
+    assert_eq!(session.unconfirmed(), 0);
+    broker.join().unwrap();
+}
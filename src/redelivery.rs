@@ -0,0 +1,276 @@
+//! Redelivery of messages which could not be handled. Instead of being
+//! NACKed straight away, and redelivered by the broker immediately and
+//! forever, a failed message is NACKed after a delay which grows with each
+//! attempt, and is moved to a dead-letter destination once it has failed too
+//! many times.
+use crate::destination::Destination;
+use crate::frame::Frame;
+use crate::header::{self, Header, HeaderList};
+use crate::transaction::RetryPolicy;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+// The number of messages whose failed attempts are remembered
+const MAX_TRACKED_MESSAGES: usize = 10_000;
+
+// Headers of a failed message which are not copied to its dead letter
+const NOT_FORWARDED: [&str; 7] = [
+    "destination",
+    "subscription",
+    "message-id",
+    "ack",
+    "content-length",
+    "redelivered",
+    "x-delivery-count",
+];
+
+/// What `Session::message_failed` does with the messages of a subscription.
+/// Each failed attempt is followed by a NACK after `retry.backoff`, and
+/// after `retry.max_attempts` attempts the message is sent to `dead_letter`
+/// and acknowledged once the broker confirms the dead letter. As messages
+/// are settled one at a time, `AckMode::Client` is replaced by
+/// `AckMode::ClientIndividual`.
+#[derive(Clone, Debug)]
+pub struct RedeliveryPolicy {
+    pub retry: RetryPolicy,
+    pub dead_letter: Destination,
+}
+
+impl RedeliveryPolicy {
+    pub fn new<D: Into<Destination>>(dead_letter: D) -> RedeliveryPolicy {
+        RedeliveryPolicy {
+            retry: RetryPolicy::default(),
+            dead_letter: dead_letter.into(),
+        }
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> RedeliveryPolicy {
+        self.retry.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Duration, max_backoff: Duration) -> RedeliveryPolicy {
+        self.retry.backoff = backoff;
+        self.retry.max_backoff = max_backoff;
+        self
+    }
+}
+
+/// The number of times a message has been delivered according to the
+/// headers set by the broker: RabbitMQ's `x-delivery-count` or the
+/// `redelivered` flag. Returns `None` if the broker sets neither.
+pub fn delivery_count(headers: &HeaderList) -> Option<u32> {
+    if let Some(count) = headers
+        .get_header("x-delivery-count")
+        .and_then(|header| header.get_value().parse::<u32>().ok())
+    {
+        return Some(count.saturating_add(1));
+    }
+    headers
+        .get_header("redelivered")
+        .map(|header| if header.get_value() == "true" { 2 } else { 1 })
+}
+
+// Counts the failed attempts to handle each message, identified by its
+// subscription and message id, forgetting the oldest beyond a limit.
+#[derive(Default)]
+pub(crate) struct DeliveryAttempts {
+    counts: HashMap<(String, String), u32>,
+    order: VecDeque<(String, String)>,
+}
+
+impl DeliveryAttempts {
+    fn key(frame: &Frame) -> Option<(String, String)> {
+        match (
+            frame.headers.get_subscription(),
+            frame.headers.get_message_id(),
+        ) {
+            (Some(header::Subscription(sub_id)), Some(header::MessageId(message_id))) => {
+                Some((sub_id.to_owned(), message_id.to_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    // Records a failed attempt, returning the number of attempts so far
+    pub fn record(&mut self, frame: &Frame) -> u32 {
+        let from_broker = delivery_count(&frame.headers).unwrap_or(1);
+        let key = match DeliveryAttempts::key(frame) {
+            Some(key) => key,
+            None => return from_broker,
+        };
+        if !self.counts.contains_key(&key) {
+            while self.counts.len() >= MAX_TRACKED_MESSAGES {
+                match self.order.pop_front() {
+                    Some(oldest) => self.counts.remove(&oldest),
+                    None => break,
+                };
+            }
+            self.order.push_back(key.clone());
+        }
+        let count = self.counts.entry(key).or_insert(0);
+        *count = from_broker.max(*count + 1);
+        *count
+    }
+
+    pub fn forget(&mut self, frame: &Frame) {
+        if let Some(key) = DeliveryAttempts::key(frame) {
+            if self.counts.remove(&key).is_some() {
+                self.order.retain(|k| *k != key);
+            }
+        }
+    }
+}
+
+impl Frame {
+    /// A copy of `message` sent to `destination`, with headers describing
+    /// why it was given up on: `x-original-destination`,
+    /// `x-original-message-id`, `x-delivery-attempts` and `x-failure-reason`.
+    pub fn dead_letter(
+        message: &Frame,
+        destination: &Destination,
+        attempts: u32,
+        reason: &str,
+    ) -> Frame {
+        let mut dead_letter = Frame::send(destination, &message.body);
+        for header in message.headers.iter() {
+            if !NOT_FORWARDED.contains(&header.get_key()) {
+                dead_letter
+                    .headers
+                    .push(message.headers.escaped(header).into_owned());
+            }
+        }
+        if let Some(header::Destination(original)) = message.headers.get_destination() {
+            dead_letter
+                .headers
+                .push(Header::new("x-original-destination", original));
+        }
+        if let Some(header::MessageId(message_id)) = message.headers.get_message_id() {
+            dead_letter
+                .headers
+                .push(Header::new("x-original-message-id", message_id));
+        }
+        dead_letter
+            .headers
+            .push(Header::new("x-delivery-attempts", &attempts.to_string()));
+        dead_letter
+            .headers
+            .push(Header::new("x-failure-reason", reason));
+        dead_letter
+    }
+}
+
+#[test]
+fn count_delivery_attempts() {
+    let mut message = Frame::send("/queue/orders", b"{}");
+    message
+        .headers
+        .push(Header::new("subscription", "stomp-rs/0"));
+    message.headers.push(Header::new("message-id", "m-1"));
+
+    let mut attempts = DeliveryAttempts::default();
+    assert_eq!(attempts.record(&message), 1);
+    assert_eq!(attempts.record(&message), 2);
+
+    // The broker's count wins when it knows of more deliveries
+    let mut redelivered = message.clone();
+    redelivered
+        .headers
+        .push(Header::new("x-delivery-count", "4"));
+    assert_eq!(attempts.record(&redelivered), 5);
+    assert_eq!(attempts.record(&message), 6);
+
+    attempts.forget(&message);
+    assert_eq!(attempts.record(&message), 1);
+    assert_eq!(
+        delivery_count(&header_list!["redelivered" => "true"]),
+        Some(2)
+    );
+}
+
+#[test]
+fn dead_letter_headers() {
+    let mut message = Frame::send("/queue/orders", b"{}");
+    message
+        .headers
+        .push(Header::new("subscription", "stomp-rs/0"));
+    message.headers.push(Header::new("message-id", "m-1"));
+    message.headers.push(Header::new("ack", "a-1"));
+    message
+        .headers
+        .push(Header::new("content-type", "application/json"));
+
+    let destination = Destination::Queue("orders.dlq".to_owned());
+    let dead_letter = Frame::dead_letter(&message, &destination, 5, "invalid: total < 0");
+    let raw: Vec<String> = dead_letter.headers.iter().map(|h| h.get_raw()).collect();
+    assert_eq!(
+        raw,
+        vec![
+            "destination:/queue/orders.dlq",
+            "content-length:2",
+            "content-type:application/json",
+            "x-original-destination:/queue/orders",
+            "x-original-message-id:m-1",
+            "x-delivery-attempts:5",
+            "x-failure-reason:invalid\\c total < 0",
+        ]
+    );
+    assert_eq!(dead_letter.body, b"{}");
+}
+
+#[test]
+fn back_off_then_dead_letter() {
+    use crate::session::SessionEvent;
+    use crate::subscription::AckMode;
+    use crate::test_broker::{broker, connected_session, header_value, run_until, Client};
+    use std::time::Instant;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let subscribe = client.read();
+        assert_eq!(header_value(&subscribe, "ack"), "client-individual");
+        let sub_id = header_value(&subscribe, "id").to_owned();
+
+        client.message(&sub_id, "1", "{}");
+        let failed = Instant::now();
+        let nack = client.read();
+        assert_eq!(nack.command.as_str(), "NACK");
+        assert_eq!(header_value(&nack, "id"), "1");
+        assert!(failed.elapsed() >= Duration::from_millis(50));
+
+        client.message(&sub_id, "1", "{}");
+        let dead_letter = client.read();
+        assert_eq!(
+            header_value(&dead_letter, "destination"),
+            "/queue/orders.dlq"
+        );
+        // The message is acknowledged once the dead letter is confirmed
+        client.quiet(Duration::from_millis(100));
+        client.receipt(&dead_letter);
+        let ack = client.read();
+        assert_eq!(ack.command.as_str(), "ACK");
+        assert_eq!(header_value(&ack, "id"), "1");
+    });
+    let (mut core, mut session) = connected_session(port);
+    session
+        .subscription("/queue/orders")
+        .with(AckMode::Client)
+        .with(
+            RedeliveryPolicy::new("/queue/orders.dlq")
+                .max_attempts(2)
+                .backoff(Duration::from_millis(50), Duration::from_millis(50)),
+        )
+        .start();
+
+    let mut handled = 0;
+    run_until(&mut core, &mut session, |session, events| {
+        for event in &events[handled..] {
+            if let SessionEvent::Message { frame, .. } = event {
+                session.message_failed(frame, "invalid order");
+            }
+        }
+        handled = events.len();
+        broker.is_finished()
+    });
+    broker.join().unwrap();
+}
@@ -0,0 +1,197 @@
+//! Request/reply messaging. A request carries `reply-to` and
+//! `correlation-id` headers; the reply is sent to the `reply-to` destination
+//! with the same `correlation-id`.
+use crate::destination::Destination;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, ToFrameBody};
+use crate::header::{CorrelationId, Header, ReplyTo};
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::Timeout;
+
+/// Where `Session::request` receives replies. Brokers differ in how a
+/// destination private to the connection is set up, so sessions making
+/// requests must be given one:
+///
+/// - `ReplyQueue::subscribed(activemq::temp_queue("replies"))` on ActiveMQ,
+///   which creates the temporary queue when it is subscribed to.
+/// - `ReplyQueue::implicit(rabbitmq::temp_queue("replies"))` on RabbitMQ,
+///   which creates it when a request names it in `reply-to`, refuses
+///   subscriptions to it, and delivers replies with a `subscription` header
+///   naming it.
+#[derive(Clone, Debug)]
+pub struct ReplyQueue {
+    pub destination: Destination,
+    /// Whether the first request subscribes to `destination`.
+    pub subscribe: bool,
+}
+
+impl ReplyQueue {
+    pub fn subscribed<D: Into<Destination>>(destination: D) -> ReplyQueue {
+        ReplyQueue {
+            destination: destination.into(),
+            subscribe: true,
+        }
+    }
+
+    pub fn implicit<D: Into<Destination>>(destination: D) -> ReplyQueue {
+        ReplyQueue {
+            destination: destination.into(),
+            subscribe: false,
+        }
+    }
+}
+
+/// Resolves to the reply to a request made with `Session::request`. Fails
+/// with `Error::RequestTimeout` if no reply arrives in time, or with
+/// `Error::Disconnected` if the connection is lost first. The `Session` must
+/// be polled for the reply to arrive.
+pub struct ReplyFuture {
+    receiver: oneshot::Receiver<Result<Frame>>,
+}
+
+impl Future for ReplyFuture {
+    type Item = Frame;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Frame, Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(Error::Disconnected),
+        }
+    }
+}
+
+// A request still waiting for its reply.
+pub(crate) struct PendingRequest {
+    sender: oneshot::Sender<Result<Frame>>,
+    deadline: Option<Timeout>,
+}
+
+impl PendingRequest {
+    pub fn new(deadline: Option<Timeout>) -> (PendingRequest, ReplyFuture) {
+        let (sender, receiver) = oneshot::channel();
+        (
+            PendingRequest { sender, deadline },
+            ReplyFuture { receiver },
+        )
+    }
+
+    pub fn resolve(self, result: Result<Frame>) {
+        let _ = self.sender.send(result);
+    }
+
+    // Whether the request has timed out.
+    pub fn poll_expired(&mut self) -> Result<bool> {
+        match self.deadline {
+            Some(ref mut deadline) => Ok(deadline.poll()?.is_ready()),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Answers requests arriving on a subscription started with
+/// `Session::serve` or `SubscriptionBuilder::serve`. Returning `None` sends
+/// no reply.
+pub type RequestHandler = Box<dyn FnMut(&Frame) -> Option<Frame>>;
+
+impl Frame {
+    /// The reply to `request`, sent to its `reply-to` destination with the
+    /// same `correlation-id`. Returns `None` if `request` has no `reply-to`.
+    pub fn reply<T: ToFrameBody>(request: &Frame, body: T) -> Option<Frame> {
+        let ReplyTo(reply_to) = request.headers.get_reply_to()?;
+        let mut reply = Frame::send(reply_to, body.to_frame_body());
+        if let Some(CorrelationId(correlation_id)) = request.headers.get_correlation_id() {
+            reply
+                .headers
+                .push(Header::new("correlation-id", correlation_id));
+        }
+        Some(reply)
+    }
+}
+
+#[test]
+fn reply_to_request() {
+    let mut request = Frame::send("/queue/rpc", b"ping");
+    assert!(Frame::reply(&request, "pong").is_none());
+
+    request
+        .headers
+        .push(Header::new("reply-to", "/temp-queue/replies"));
+    request
+        .headers
+        .push(Header::new("correlation-id", "request/3"));
+    let reply = Frame::reply(&request, "pong").unwrap();
+    assert_eq!(
+        reply.headers.get_destination().map(|d| d.0),
+        Some("/temp-queue/replies")
+    );
+    assert_eq!(
+        reply.headers.get_correlation_id().map(|c| c.0),
+        Some("request/3")
+    );
+    assert_eq!(reply.body, b"pong");
+}
+
+#[test]
+fn request_and_reply() {
+    use crate::dialect::activemq;
+    use crate::session_builder::SessionBuilder;
+    use crate::subscription::AckMode;
+    use crate::test_broker::{broker, connected, header_value, run_until, wait, Client};
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let serving = client.read();
+        assert_eq!(header_value(&serving, "destination"), "/queue/rpc");
+        assert_eq!(header_value(&serving, "ack"), "client");
+        let replies = client.read();
+        assert_eq!(header_value(&replies, "destination"), "/temp-queue/replies");
+        let request = client.read();
+        assert_eq!(header_value(&request, "reply-to"), "/temp-queue/replies");
+        let correlation_id = header_value(&request, "correlation-id").to_owned();
+
+        // The request reaches the serving subscription
+        client.write(&format!(
+            "MESSAGE\nsubscription:{}\nmessage-id:1\nack:1\ndestination:/queue/rpc\n\
+             reply-to:/temp-queue/replies\ncorrelation-id:{}\n\nping\0",
+            header_value(&serving, "id"),
+            correlation_id
+        ));
+        let reply = client.read();
+        assert_eq!(header_value(&reply, "destination"), "/temp-queue/replies");
+        assert_eq!(header_value(&reply, "correlation-id"), correlation_id);
+        assert_eq!(reply.body, b"pong");
+        let ack = client.read();
+        assert_eq!(ack.command.as_str(), "ACK");
+        assert_eq!(header_value(&ack, "id"), "1");
+
+        // The reply reaches the session's reply subscription
+        client.write(&format!(
+            "MESSAGE\nsubscription:{}\nmessage-id:2\ndestination:/temp-queue/replies\n\
+             correlation-id:{}\n\npong\0",
+            header_value(&replies, "id"),
+            correlation_id
+        ));
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ReplyQueue::subscribed(activemq::temp_queue("replies")))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+
+    session
+        .subscription("/queue/rpc")
+        .with(AckMode::Client)
+        .serve(|request| Frame::reply(request, "pong"));
+    let reply = session.request("/queue/rpc", "ping", Duration::from_secs(10));
+    let reply = wait(&mut core, &mut session, reply).unwrap();
+    assert_eq!(reply.body, b"pong");
+    broker.join().unwrap();
+}
@@ -0,0 +1,706 @@
+//! Client-side evaluation of message selectors: the SQL-92 conditional
+//! expressions over message headers understood by ActiveMQ and Artemis, e.g.
+//! `region = 'EU' AND priority > 4`. Brokers which ignore the `selector`
+//! header, such as RabbitMQ, deliver every message, so a `Filter` added to a
+//! subscription drops the messages it does not match before they become
+//! events. Dropped messages are acknowledged with `AckMode::ClientIndividual`.
+//! With `AckMode::Client` they are not, as acknowledging one would also
+//! acknowledge the messages received before it; the next message the
+//! application acknowledges covers them.
+//!
+//! The supported subset is: string, numeric and boolean literals; header
+//! names; `+ - * /`; `= <> < > <= >=`; `AND`, `OR` and `NOT`; `[NOT] BETWEEN`,
+//! `[NOT] IN`, `[NOT] LIKE ... [ESCAPE ...]` and `IS [NOT] NULL`. Header values
+//! are strings, converted to numbers or booleans when compared with one.
+//! Missing headers are NULL, and a condition involving NULL is neither true
+//! nor false, as in SQL.
+use crate::header::HeaderList;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed selector which can be evaluated against received messages.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    selector: String,
+    expr: Expr,
+}
+
+/// A selector which could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidSelector {
+    /// The byte offset in the selector at which parsing failed.
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid selector at offset {}: {}",
+            self.position, self.reason
+        )
+    }
+}
+
+impl error::Error for InvalidSelector {}
+
+impl Filter {
+    /// The selector as it was written, sent to the broker in the `selector`
+    /// header.
+    pub fn selector(&self) -> &str {
+        &self.selector
+    }
+
+    /// Whether the selector is true for `headers`. Unknown results, e.g.
+    /// from comparing a missing header, do not match.
+    pub fn matches(&self, headers: &HeaderList) -> bool {
+        self.expr.eval(headers).truth() == Some(true)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = InvalidSelector;
+
+    fn from_str(selector: &str) -> Result<Filter, InvalidSelector> {
+        let mut parser = Parser {
+            tokens: tokenize(selector)?,
+            next: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            (_, Token::End) => Ok(Filter {
+                selector: selector.to_owned(),
+                expr,
+            }),
+            (position, _) => Err(InvalidSelector {
+                position,
+                reason: "unexpected token",
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.selector)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl Value {
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Text(text) => text.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    // The value as a condition: None if it is unknown or not a boolean
+    fn truth(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            Value::Text(text) if text.eq_ignore_ascii_case("true") => Some(true),
+            Value::Text(text) if text.eq_ignore_ascii_case("false") => Some(false),
+            _ => None,
+        }
+    }
+
+    fn from_truth(truth: Option<bool>) -> Value {
+        truth.map_or(Value::Null, Value::Bool)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Header(String),
+    Negate(Box<Expr>),
+    Arithmetic(Box<Expr>, Arithmetic, Box<Expr>),
+    Compare(Box<Expr>, Comparison, Box<Expr>),
+    Between {
+        value: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+    },
+    In {
+        value: Box<Expr>,
+        list: Vec<Value>,
+    },
+    Like {
+        value: Box<Expr>,
+        pattern: Vec<char>,
+        escape: Option<char>,
+    },
+    IsNull(Box<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+fn compare(left: &Value, op: Comparison, right: &Value) -> Option<bool> {
+    use std::cmp::Ordering;
+
+    let ordering = match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => return None,
+        (Value::Number(_), _) | (_, Value::Number(_)) => {
+            left.number()?.partial_cmp(&right.number()?)?
+        }
+        (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+            let equal = left.truth()? == right.truth()?;
+            return match op {
+                Comparison::Equal => Some(equal),
+                Comparison::NotEqual => Some(!equal),
+                _ => None,
+            };
+        }
+        // Strings can only be tested for equality
+        (Value::Text(l), Value::Text(r)) => {
+            return match op {
+                Comparison::Equal => Some(l == r),
+                Comparison::NotEqual => Some(l != r),
+                _ => None,
+            };
+        }
+    };
+    Some(match op {
+        Comparison::Equal => ordering == Ordering::Equal,
+        Comparison::NotEqual => ordering != Ordering::Equal,
+        Comparison::Less => ordering == Ordering::Less,
+        Comparison::Greater => ordering == Ordering::Greater,
+        Comparison::LessOrEqual => ordering != Ordering::Greater,
+        Comparison::GreaterOrEqual => ordering != Ordering::Less,
+    })
+}
+
+// An element of a LIKE pattern
+#[derive(Clone, Copy, PartialEq)]
+enum Wildcard {
+    Any,
+    One,
+    Literal(char),
+}
+
+// Matches `text` against a LIKE pattern, where `%` matches any sequence of
+// characters and `_` any one character. A mismatch resumes after the last
+// `%`, which then matches one more character, so matching takes at most
+// `text.len() * pattern.len()` steps.
+fn like(text: &[char], pattern: &[char], escape: Option<char>) -> bool {
+    let mut wildcards = Vec::with_capacity(pattern.len());
+    let mut chars = pattern.iter();
+    while let Some(&c) = chars.next() {
+        wildcards.push(match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(&literal) => Wildcard::Literal(literal),
+                // An escape character ending the pattern matches nothing
+                None => return false,
+            },
+            '%' => Wildcard::Any,
+            '_' => Wildcard::One,
+            c => Wildcard::Literal(c),
+        });
+    }
+    let (mut t, mut p) = (0, 0);
+    // The position of the last `%`, and of the text it matched up to
+    let mut last_any = None;
+    while t < text.len() {
+        match wildcards.get(p) {
+            Some(Wildcard::Any) => {
+                last_any = Some((p, t));
+                p += 1;
+            }
+            Some(Wildcard::One) => {
+                t += 1;
+                p += 1;
+            }
+            Some(Wildcard::Literal(c)) if *c == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            _ => match last_any {
+                Some((any, matched)) => {
+                    last_any = Some((any, matched + 1));
+                    p = any + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    wildcards[p..].iter().all(|w| *w == Wildcard::Any)
+}
+
+impl Expr {
+    fn eval(&self, headers: &HeaderList) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Header(name) => headers.get_header(name).map_or(Value::Null, |header| {
+                Value::Text(header.get_value().to_owned())
+            }),
+            Expr::Negate(operand) => operand
+                .eval(headers)
+                .number()
+                .map_or(Value::Null, |n| Value::Number(-n)),
+            Expr::Arithmetic(left, op, right) => {
+                let operands = (left.eval(headers).number(), right.eval(headers).number());
+                match operands {
+                    (Some(l), Some(r)) => Value::Number(match op {
+                        Arithmetic::Add => l + r,
+                        Arithmetic::Subtract => l - r,
+                        Arithmetic::Multiply => l * r,
+                        Arithmetic::Divide => l / r,
+                    }),
+                    _ => Value::Null,
+                }
+            }
+            Expr::Compare(left, op, right) => {
+                Value::from_truth(compare(&left.eval(headers), *op, &right.eval(headers)))
+            }
+            Expr::Between { value, low, high } => {
+                let value = value.eval(headers);
+                let above = compare(&value, Comparison::GreaterOrEqual, &low.eval(headers));
+                let below = compare(&value, Comparison::LessOrEqual, &high.eval(headers));
+                Value::from_truth(and(above, below))
+            }
+            Expr::In { value, list } => match value.eval(headers) {
+                Value::Null => Value::Null,
+                value => Value::Bool(
+                    list.iter()
+                        .any(|item| compare(&value, Comparison::Equal, item) == Some(true)),
+                ),
+            },
+            Expr::Like {
+                value,
+                pattern,
+                escape,
+            } => match value.eval(headers) {
+                Value::Text(text) => {
+                    let text: Vec<char> = text.chars().collect();
+                    Value::Bool(like(&text, pattern, *escape))
+                }
+                _ => Value::Null,
+            },
+            Expr::IsNull(value) => Value::Bool(value.eval(headers) == Value::Null),
+            Expr::Not(operand) => Value::from_truth(operand.eval(headers).truth().map(|b| !b)),
+            Expr::And(left, right) => {
+                Value::from_truth(and(left.eval(headers).truth(), right.eval(headers).truth()))
+            }
+            Expr::Or(left, right) => {
+                let (left, right) = (left.eval(headers).truth(), right.eval(headers).truth());
+                Value::from_truth(match (left, right) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                })
+            }
+        }
+    }
+}
+
+fn and(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Text(String),
+    Number(f64),
+    Symbol(&'static str),
+    End,
+}
+
+const SYMBOLS: [&str; 12] = [
+    "<>", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "(", ")",
+];
+
+fn tokenize(selector: &str) -> Result<Vec<(usize, Token)>, InvalidSelector> {
+    let mut tokens = Vec::new();
+    let mut rest = selector;
+    loop {
+        rest = rest.trim_start();
+        let position = selector.len() - rest.len();
+        let error = |reason| InvalidSelector { position, reason };
+        let first = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        let (token, length) = if first == '\'' {
+            // Quotes within strings are doubled
+            let mut text = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((i, '\'')) => match chars.next() {
+                        Some((_, '\'')) => text.push('\''),
+                        _ => break i + 1,
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(error("unterminated string")),
+                }
+            };
+            (Token::Text(text), end)
+        } else if first.is_ascii_digit() || first == '.' {
+            let length = rest
+                .char_indices()
+                .find(|&(i, c)| {
+                    !(c.is_ascii_alphanumeric()
+                        || c == '.'
+                        || ((c == '+' || c == '-') && rest[..i].ends_with(['e', 'E'])))
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            let number = rest[..length]
+                .parse()
+                .map_err(|_| error("invalid number"))?;
+            (Token::Number(number), length)
+        } else if first.is_alphabetic() || first == '_' || first == '$' {
+            let length = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            (Token::Identifier(rest[..length].to_owned()), length)
+        } else if first == ',' {
+            (Token::Symbol(","), 1)
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| error("unexpected character"))?;
+            (Token::Symbol(symbol), symbol.len())
+        };
+        tokens.push((position, token));
+        rest = &rest[length..];
+    }
+    tokens.push((selector.len(), Token::End));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> (usize, &Token) {
+        let (position, token) = &self.tokens[self.next];
+        (*position, token)
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, reason: &'static str) -> Result<T, InvalidSelector> {
+        Err(InvalidSelector {
+            position: self.peek().0,
+            reason,
+        })
+    }
+
+    // Consumes the next token if it is the keyword `keyword`
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek().1 {
+            Token::Identifier(word) if word.eq_ignore_ascii_case(keyword) => {
+                self.advance();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.peek().1 {
+            Token::Symbol(s) if *s == symbol => {
+                self.advance();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, InvalidSelector> {
+        let mut expr = self.and()?;
+        while self.keyword("OR") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, InvalidSelector> {
+        let mut expr = self.not()?;
+        while self.keyword("AND") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, InvalidSelector> {
+        if self.keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr, InvalidSelector> {
+        let value = Box::new(self.sum()?);
+        let comparisons = [
+            ("=", Comparison::Equal),
+            ("<>", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        for (symbol, comparison) in comparisons.iter() {
+            if self.symbol(symbol) {
+                return Ok(Expr::Compare(value, *comparison, Box::new(self.sum()?)));
+            }
+        }
+        if self.keyword("IS") {
+            let negated = self.keyword("NOT");
+            if !self.keyword("NULL") {
+                return self.error("expected NULL");
+            }
+            return Ok(negate(Expr::IsNull(value), negated));
+        }
+        let negated = self.keyword("NOT");
+        let expr = if self.keyword("BETWEEN") {
+            let low = Box::new(self.sum()?);
+            if !self.keyword("AND") {
+                return self.error("expected AND");
+            }
+            let high = Box::new(self.sum()?);
+            Expr::Between { value, low, high }
+        } else if self.keyword("IN") {
+            Expr::In {
+                value,
+                list: self.list()?,
+            }
+        } else if self.keyword("LIKE") {
+            let pattern = self.string()?.chars().collect();
+            let escape = if self.keyword("ESCAPE") {
+                let escape = self.string()?;
+                let mut chars = escape.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => return self.error("escape must be one character"),
+                }
+            } else {
+                None
+            };
+            Expr::Like {
+                value,
+                pattern,
+                escape,
+            }
+        } else if negated {
+            return self.error("expected BETWEEN, IN or LIKE");
+        } else {
+            return Ok(*value);
+        };
+        Ok(negate(expr, negated))
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>, InvalidSelector> {
+        if !self.symbol("(") {
+            return self.error("expected (");
+        }
+        let mut list = Vec::new();
+        loop {
+            let item = match self.peek().1 {
+                Token::Text(text) => Value::Text(text.clone()),
+                Token::Number(number) => Value::Number(*number),
+                _ => return self.error("expected a literal"),
+            };
+            list.push(item);
+            self.advance();
+            if self.symbol(")") {
+                return Ok(list);
+            }
+            if !self.symbol(",") {
+                return self.error("expected , or )");
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, InvalidSelector> {
+        match self.peek().1 {
+            Token::Text(text) => {
+                let text = text.clone();
+                self.advance();
+                Ok(text)
+            }
+            _ => self.error("expected a string"),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, InvalidSelector> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.symbol("+") {
+                Arithmetic::Add
+            } else if self.symbol("-") {
+                Arithmetic::Subtract
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, InvalidSelector> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.symbol("*") {
+                Arithmetic::Multiply
+            } else if self.symbol("/") {
+                Arithmetic::Divide
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, InvalidSelector> {
+        if self.symbol("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.symbol("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, InvalidSelector> {
+        if self.symbol("(") {
+            let expr = self.or()?;
+            if !self.symbol(")") {
+                return self.error("expected )");
+            }
+            return Ok(expr);
+        }
+        let expr = match self.peek().1 {
+            Token::Text(text) => Expr::Literal(Value::Text(text.clone())),
+            Token::Number(number) => Expr::Literal(Value::Number(*number)),
+            Token::Identifier(word) if word.eq_ignore_ascii_case("TRUE") => {
+                Expr::Literal(Value::Bool(true))
+            }
+            Token::Identifier(word) if word.eq_ignore_ascii_case("FALSE") => {
+                Expr::Literal(Value::Bool(false))
+            }
+            Token::Identifier(word) if is_reserved(word) => {
+                return self.error("unexpected keyword")
+            }
+            Token::Identifier(name) => Expr::Header(name.clone()),
+            _ => return self.error("expected a value"),
+        };
+        self.advance();
+        Ok(expr)
+    }
+}
+
+fn negate(expr: Expr, negated: bool) -> Expr {
+    if negated {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    [
+        "AND", "OR", "NOT", "BETWEEN", "IN", "LIKE", "ESCAPE", "IS", "NULL",
+    ]
+    .iter()
+    .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+#[test]
+fn evaluate_selectors() {
+    use crate::header::Header;
+
+    let headers = header_list![
+        "region" => "EU",
+        "priority" => "7",
+        "type" => "order_created",
+        "urgent" => "true"
+    ];
+    let matches = |selector: &str| selector.parse::<Filter>().unwrap().matches(&headers);
+
+    assert!(matches("region = 'EU' AND priority > 4"));
+    assert!(matches("priority * 2 - 4 = 10 AND NOT region <> 'EU'"));
+    assert!(matches(
+        "priority BETWEEN 5 AND 9 AND region IN ('EU', 'UK')"
+    ));
+    assert!(matches(
+        "type LIKE 'order%' AND type LIKE 'order\\_cr_ated' ESCAPE '\\'"
+    ));
+    assert!(matches(
+        "urgent = TRUE AND missing IS NULL AND region IS NOT NULL"
+    ));
+    assert!(matches("region NOT IN ('US') OR missing = 1"));
+    assert!(!matches("region = 'eu'"));
+    assert!(!matches("priority NOT BETWEEN 5 AND 9"));
+    assert!(!matches("type NOT LIKE '%created'"));
+    assert!(matches("type LIKE '%_%_%e_'"));
+    assert!(!matches("type LIKE 'order_created_'"));
+    assert!(!matches("type LIKE '%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%x'"));
+
+    // Comparisons with a missing header are unknown, so neither they nor
+    // their negations match
+    assert!(!matches("missing = 'x'"));
+    assert!(!matches("NOT missing = 'x'"));
+    assert!(!matches("missing > 1 AND region = 'EU'"));
+    assert!(matches("missing > 1 OR region = 'EU'"));
+    assert!(!matches("region > 'A'"));
+}
+
+#[test]
+fn reject_invalid_selectors() {
+    let error = |selector: &str| selector.parse::<Filter>().unwrap_err();
+    assert_eq!(error("region = 'EU").position, 9);
+    assert_eq!(error("region = ").reason, "expected a value");
+    assert_eq!(error("priority > 4 AND").position, 16);
+    assert_eq!(error("region IN ('EU' 'UK')").reason, "expected , or )");
+    assert_eq!(error("region = 'EU' )").reason, "unexpected token");
+    assert_eq!(error("priority ! 4").reason, "unexpected character");
+    assert_eq!(
+        error("region NOT = 'EU'").reason,
+        "expected BETWEEN, IN or LIKE"
+    );
+}
@@ -1,30 +1,82 @@
+use crate::body::{BodyReader, BodySender, Outbound, OutboundBody};
 use crate::codec::Codec;
 use crate::connection::{self, Connection};
+use crate::consumer::{Consumer, ConsumerStopped, Outcome};
+use crate::destination::Destination;
+use crate::error::{BrokerError, Error, Result};
 use crate::frame::Transmission::{self, CompleteFrame, HeartBeat};
 use crate::frame::{Command, Frame, ToFrameBody};
 use crate::header::{self, Header};
+use crate::interceptor::{self, BeforeReceive, BeforeSend};
 use crate::message_builder::MessageBuilder;
+use crate::publish::{ReliablePublish, Unconfirmed};
+use crate::redelivery::DeliveryAttempts;
+use crate::request::{PendingRequest, ReplyFuture, RequestHandler};
 use crate::session_builder::SessionConfig;
-use crate::subscription::{AckMode, AckOrNack, Subscription};
+use crate::spool::SpoolFile;
+use crate::subscription::{AckMode, AckOrNack, Subscription, Unsubscribe};
 use crate::subscription_builder::SubscriptionBuilder;
+use futures::sync::oneshot;
 use futures::*;
 use std::collections::hash_map::HashMap;
-use std::io::Result;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use tokio::codec::Framed;
 use tokio_core::net::{TcpStream, TcpStreamNew};
 use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncRead;
 
-use crate::transaction::Transaction;
+use crate::transaction::{
+    JobState, RetryPolicy, Transaction, TransactionLog, TransactionScope, TransactionalFuture,
+    TransactionalJob, WorkFuture,
+};
 
 const GRACE_PERIOD_MULTIPLIER: f32 = 2.0;
+// How many heartbeat periods the deadline for hearing from the broker can be
+// extended by while reads are paused
+const MAX_PAUSED_HEARTBEAT_PERIODS: u64 = 4;
 
 pub struct OutstandingReceipt {
     pub original_frame: Frame,
+    notify: Option<oneshot::Sender<Result<Frame>>>,
 }
 
 impl OutstandingReceipt {
     pub fn new(original_frame: Frame) -> Self {
-        OutstandingReceipt { original_frame }
+        OutstandingReceipt {
+            original_frame,
+            notify: None,
+        }
+    }
+
+    // Resolves the `ReceiptFuture` waiting for this receipt, if any.
+    fn resolve(&mut self, result: Result<Frame>) {
+        if let Some(notify) = self.notify.take() {
+            let _ = notify.send(result);
+        }
+    }
+}
+
+/// Resolves to the RECEIPT frame once the broker has processed the frame
+/// which requested it, or fails with the broker's ERROR. Fails with
+/// `Error::Disconnected` if the connection is lost first. The `Session` must
+/// be polled for the receipt to arrive.
+pub struct ReceiptFuture {
+    receiver: oneshot::Receiver<Result<Frame>>,
+}
+
+impl Future for ReceiptFuture {
+    type Item = Frame;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Frame, Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(Error::Disconnected),
+        }
     }
 }
 pub struct GenerateReceipt;
@@ -43,12 +95,26 @@ pub struct SessionState {
     next_transaction_id: u32,
     next_subscription_id: u32,
     next_receipt_id: u32,
+    next_request_id: u32,
     pub rx_heartbeat_ms: Option<u32>,
     pub tx_heartbeat_ms: Option<u32>,
     pub rx_heartbeat_timeout: Option<Timeout>,
     pub tx_heartbeat_timeout: Option<Timeout>,
+    // When reads were paused for the body's reader or a consumer
+    reads_paused_since: Option<Instant>,
     pub subscriptions: HashMap<String, Subscription>,
     pub outstanding_receipts: HashMap<String, OutstandingReceipt>,
+    pub(crate) transactions: HashMap<String, TransactionLog>,
+    reply_subscription: Option<String>,
+    pending_requests: HashMap<String, PendingRequest>,
+    pub(crate) request_handlers: HashMap<String, RequestHandler>,
+    pub(crate) delivery_attempts: DeliveryAttempts,
+    delayed_nacks: Vec<(Timeout, Frame)>,
+    // Failed messages waiting for the receipt of their dead letter
+    dead_letters: Vec<(ReceiptFuture, Frame)>,
+    pub(crate) unconfirmed: Unconfirmed,
+    pub(crate) spool: Option<SpoolFile>,
+    pub(crate) consumers: HashMap<String, Consumer>,
 }
 
 impl SessionState {
@@ -57,12 +123,24 @@ impl SessionState {
             next_transaction_id: 0,
             next_subscription_id: 0,
             next_receipt_id: 0,
+            next_request_id: 0,
             rx_heartbeat_ms: None,
             rx_heartbeat_timeout: None,
             tx_heartbeat_ms: None,
             tx_heartbeat_timeout: None,
+            reads_paused_since: None,
             subscriptions: HashMap::new(),
             outstanding_receipts: HashMap::new(),
+            transactions: HashMap::new(),
+            reply_subscription: None,
+            pending_requests: HashMap::new(),
+            request_handlers: HashMap::new(),
+            delivery_attempts: DeliveryAttempts::default(),
+            delayed_nacks: Vec::new(),
+            dead_letters: Vec::new(),
+            unconfirmed: Unconfirmed::default(),
+            spool: None,
+            consumers: HashMap::new(),
         }
     }
 }
@@ -70,37 +148,232 @@ impl SessionState {
 // *** Public API ***
 impl Session {
     pub fn send_frame(&mut self, fr: Frame) {
+        self.send(Transmission::CompleteFrame(fr));
+    }
+    // Sends a frame, returning false if it was dropped by an interceptor or
+    // rejected rather than sent or queued.
+    pub(crate) fn try_send_frame(&mut self, fr: Frame) -> bool {
         self.send(Transmission::CompleteFrame(fr))
     }
-    pub fn message<T: ToFrameBody>(
+    pub fn message<D: Into<Destination>, T: ToFrameBody>(
         &mut self,
-        destination: &str,
+        destination: D,
         body_convertible: T,
-    ) -> MessageBuilder {
+    ) -> MessageBuilder<'_> {
         let send_frame = Frame::send(destination, body_convertible.to_frame_body());
         MessageBuilder::new(self, send_frame)
     }
 
-    pub fn subscription(&mut self, destination: &str) -> SubscriptionBuilder {
-        SubscriptionBuilder::new(self, destination.to_owned())
+    /// Prepares a message whose body is read from `body` while it is being
+    /// sent, rather than being held in memory. `body` must provide exactly
+    /// `content_length` bytes. Other frames sent in the meantime are queued
+    /// until the whole body has been written.
+    pub fn message_stream<D: Into<Destination>, R: AsyncRead + 'static>(
+        &mut self,
+        destination: D,
+        content_length: u64,
+        body: R,
+    ) -> MessageBuilder<'_> {
+        let mut send_frame = Frame::send(destination, &[]);
+        send_frame.headers.insert(Header::new(
+            "content-length",
+            content_length.to_string().as_ref(),
+        ));
+        let mut builder = MessageBuilder::new(self, send_frame);
+        builder.body_reader = Some((Box::new(body), content_length));
+        builder
+    }
+
+    pub fn subscription<D: Into<Destination>>(
+        &mut self,
+        destination: D,
+    ) -> SubscriptionBuilder<'_> {
+        SubscriptionBuilder::new(self, destination.into())
     }
 
-    pub fn begin_transaction(&mut self) -> Transaction {
+    pub fn begin_transaction(&mut self) -> Transaction<'_> {
         let mut transaction = Transaction::new(self);
         transaction.begin();
         transaction
     }
 
+    /// Runs the unit of work returned by `work` in a transaction and commits
+    /// it, requesting a receipt. `work` is given the `TransactionScope`
+    /// through which the unit of work sends its messages and
+    /// acknowledgements. If the unit of work fails the transaction is
+    /// aborted. If the broker refuses the commit, or the connection is lost
+    /// before it is confirmed, `work` is called again for a new transaction
+    /// as allowed by `policy`; after a lost connection, the retry waits for
+    /// `reconnect` to be called. The first attempt starts once the session is
+    /// polled.
+    pub fn transactional<F, W>(&mut self, policy: RetryPolicy, mut work: F) -> TransactionalFuture
+    where
+        F: FnMut(TransactionScope) -> W + 'static,
+        W: IntoFuture<Item = (), Error = Error>,
+        W::Future: 'static,
+    {
+        let work = move |scope| -> WorkFuture { Box::new(work(scope).into_future()) };
+        let (job, future) = TransactionalJob::new(Box::new(work), policy);
+        self.transactional_jobs.push(job);
+        if task::is_in_task() {
+            task::current().notify();
+        }
+        future
+    }
+
+    /// Sends a request to `destination` and waits up to `timeout` for the
+    /// reply. Replies are received on the session's `ReplyQueue`, which the
+    /// first request subscribes to if need be. Fails with
+    /// `Error::InvalidUsage` if the session has no `ReplyQueue`.
+    pub fn request<D: Into<Destination>, T: ToFrameBody>(
+        &mut self,
+        destination: D,
+        body: T,
+        timeout: Duration,
+    ) -> ReplyFuture {
+        let frame = Frame::send(destination, body.to_frame_body());
+        self.request_frame(frame, Some(timeout))
+    }
+
+    /// Sends `frame` as a request, adding `reply-to` and `correlation-id`
+    /// headers. Without a `timeout`, the reply is awaited until the
+    /// connection is lost.
+    pub fn request_frame(&mut self, mut frame: Frame, timeout: Option<Duration>) -> ReplyFuture {
+        let deadline = match timeout.map(|timeout| Timeout::new(timeout, &self.hdl)) {
+            Some(Ok(deadline)) => Some(deadline),
+            Some(Err(e)) => {
+                let (request, reply) = PendingRequest::new(None);
+                request.resolve(Err(e.into()));
+                return reply;
+            }
+            None => None,
+        };
+        let reply_queue = match self.config.reply_queue {
+            Some(ref reply_queue) => reply_queue.clone(),
+            None => {
+                let (request, reply) = PendingRequest::new(None);
+                request.resolve(Err(Error::InvalidUsage(
+                    "requests need a ReplyQueue".to_owned(),
+                )));
+                return reply;
+            }
+        };
+        let reply_to = reply_queue.destination.to_string();
+        if self.state.reply_subscription.is_none() {
+            // Replies to an implicit queue name it as their subscription
+            let id = if reply_queue.subscribe {
+                self.subscription(reply_queue.destination).start()
+            } else {
+                reply_to.clone()
+            };
+            self.state.reply_subscription = Some(id);
+        }
+        let correlation_id = format!("request/{}", self.state.next_request_id);
+        self.state.next_request_id += 1;
+        frame.headers.insert(Header::new("reply-to", &reply_to));
+        frame
+            .headers
+            .insert(Header::new("correlation-id", &correlation_id));
+        let (request, reply) = PendingRequest::new(deadline);
+        self.state.pending_requests.insert(correlation_id, request);
+        self.send_frame(frame);
+        if !matches!(self.stream, StreamState::Connected(_)) {
+            // The request was dropped, so no reply will ever arrive
+            self.fail_requests();
+        }
+        reply
+    }
+
+    /// Sends the reply to a request received in a `SessionEvent::Message`.
+    /// Returns `false` if the request has no `reply-to` header.
+    pub fn reply<T: ToFrameBody>(&mut self, request: &Frame, body: T) -> bool {
+        match Frame::reply(request, body) {
+            Some(reply) => {
+                self.send_frame(reply);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribes to `destination` and answers each request arriving there
+    /// with the reply returned by `handler`, as `SubscriptionBuilder::serve`
+    /// does. Returns the subscription id.
+    pub fn serve<D, F>(&mut self, destination: D, handler: F) -> String
+    where
+        D: Into<Destination>,
+        F: FnMut(&Frame) -> Option<Frame> + 'static,
+    {
+        self.subscription(destination).serve(handler)
+    }
+
+    /// Stops the consumer of the subscription `sub_id`, started with
+    /// `SubscriptionBuilder::consume`. The messages it has received are
+    /// handled, and the subscription ends once they have been. Messages
+    /// arriving meanwhile are not handled nor acknowledged, so the broker
+    /// redelivers them unless the subscription uses `AckMode::Auto`.
+    pub fn stop_consumer(&mut self, sub_id: &str) -> ConsumerStopped {
+        match self.state.consumers.get_mut(sub_id) {
+            Some(consumer) => consumer.stop(),
+            None => ConsumerStopped::immediately(),
+        }
+    }
+
+    /// The number of messages of the subscription `sub_id` dropped as
+    /// duplicates since it started, if it has `Deduplicate`.
+    pub fn duplicates(&self, sub_id: &str) -> Option<u64> {
+        self.state
+            .subscriptions
+            .get(sub_id)
+            .and_then(|sub| sub.seen.as_ref())
+            .map(|seen| seen.duplicates())
+    }
+
+    /// Ends the subscription `sub_id`. A durable subscription is kept by the
+    /// broker, as with `Unsubscribe::Detach`.
     pub fn unsubscribe(&mut self, sub_id: &str) {
-        self.state.subscriptions.remove(sub_id);
-        let unsubscribe_frame = Frame::unsubscribe(sub_id);
-        self.send(CompleteFrame(unsubscribe_frame))
+        self.unsubscribe_with(sub_id, Unsubscribe::Detach);
+    }
+
+    /// Ends the subscription `sub_id`. A durable subscription is kept by the
+    /// broker unless `mode` is `Unsubscribe::Destroy`.
+    pub fn unsubscribe_with(&mut self, sub_id: &str, mode: Unsubscribe) {
+        self.state.request_handlers.remove(sub_id);
+        if let Some(consumer) = self.state.consumers.remove(sub_id) {
+            consumer.stopped();
+        }
+        let subscription = self.state.subscriptions.remove(sub_id);
+        let mut unsubscribe_frame = Frame::unsubscribe(sub_id);
+        if let (Unsubscribe::Destroy, Some(mut durable)) =
+            (mode, subscription.and_then(|sub| sub.durable))
+        {
+            unsubscribe_frame.headers.concat(&mut durable);
+        }
+        self.send(CompleteFrame(unsubscribe_frame));
+    }
+
+    /// Calls `hook` with every frame before it is sent. Returning `false`
+    /// from it drops the frame.
+    pub fn on_before_send<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut Frame) -> bool + 'static,
+    {
+        self.config.interceptors.push(Rc::new(BeforeSend(hook)));
+    }
+
+    /// Calls `hook` with every frame received, before the session acts on
+    /// it. Returning `false` from it drops the frame.
+    pub fn on_before_receive<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut Frame) -> bool + 'static,
+    {
+        self.config.interceptors.push(Rc::new(BeforeReceive(hook)));
     }
 
     pub fn disconnect(&mut self) {
         self.send_frame(Frame::disconnect());
     }
-    pub fn reconnect(&mut self) -> ::std::io::Result<()> {
+    pub fn reconnect(&mut self) -> Result<()> {
         use std::io;
         use std::net::ToSocketAddrs;
 
@@ -108,22 +381,64 @@ impl Session {
 
         let address = (&self.config.host as &str, self.config.port)
             .to_socket_addrs()?
-            .nth(0)
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::Other, "address provided resolved to nothing")
-            })?;
+            .next()
+            .ok_or_else(|| io::Error::other("address provided resolved to nothing"))?;
         self.stream = StreamState::Connecting(TcpStream::connect(&address, &self.hdl));
+        self.stomp_connected = false;
         task::current().notify();
         Ok(())
     }
     pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) {
-        if let Some(header::Ack(ack_id)) = frame.headers.get_ack() {
-            let ack_frame = if let AckOrNack::Ack = which {
-                Frame::ack(ack_id)
-            } else {
-                Frame::nack(ack_id)
-            };
-            self.send_frame(ack_frame);
+        if let AckOrNack::Ack = which {
+            self.state.delivery_attempts.forget(frame);
+        }
+        let ack_frame = match Frame::acknowledgement(frame, which) {
+            Some(ack_frame) => ack_frame,
+            None => return,
+        };
+        if self.try_send_frame(ack_frame)
+            && matches!(which, AckOrNack::Ack)
+            && matches!(self.stream, StreamState::Connected(_))
+        {
+            self.remember_handled(frame);
+        }
+    }
+
+    /// Reports that a received message could not be handled, for `reason`.
+    /// Without a `RedeliveryPolicy` on its subscription, the message is
+    /// NACKed. Otherwise it is NACKed after the policy's backoff, or, once it
+    /// has failed `max_attempts` times, sent to the dead-letter destination
+    /// and acknowledged once the broker has confirmed the dead letter with a
+    /// receipt. Messages of `AckMode::Auto` subscriptions cannot be
+    /// redelivered and are dead-lettered straight away.
+    pub fn message_failed(&mut self, frame: &Frame, reason: &str) {
+        let policy = frame
+            .headers
+            .get_subscription()
+            .and_then(|header::Subscription(sub_id)| self.state.subscriptions.get(sub_id))
+            .and_then(|sub| sub.redelivery.clone());
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return self.acknowledge_frame(frame, AckOrNack::Nack),
+        };
+        let attempts = self.state.delivery_attempts.record(frame);
+        match Frame::acknowledgement(frame, AckOrNack::Nack) {
+            Some(nack_frame) if attempts < policy.retry.max_attempts => {
+                let delay = policy.retry.backoff(attempts);
+                match Timeout::new(delay, &self.hdl) {
+                    Ok(timeout) => self.state.delayed_nacks.push((timeout, nack_frame)),
+                    Err(_) => self.send_frame(nack_frame),
+                }
+            }
+            _ => {
+                warn!(
+                    "Moving a message to {} after {} failed attempts: {}",
+                    policy.dead_letter, attempts, reason
+                );
+                let dead_letter = Frame::dead_letter(frame, &policy.dead_letter, attempts, reason);
+                let confirmed = self.send_with_receipt(dead_letter);
+                self.state.dead_letters.push((confirmed, frame.clone()));
+            }
         }
     }
 }
@@ -136,6 +451,23 @@ impl Session {
             state: SessionState::new(),
             events: vec![],
             stream: StreamState::Connecting(stream),
+            stomp_connected: false,
+            outbound: VecDeque::new(),
+            inbound_body: None,
+            transactional_jobs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn send_streamed(
+        &mut self,
+        mut frame: Frame,
+        body: Box<dyn AsyncRead>,
+        content_length: u64,
+    ) {
+        if self.prepare_frame(&mut frame) {
+            self.record_transactional(&frame);
+            let body = OutboundBody::new(frame, body, content_length);
+            self.send_outbound(Outbound::Streamed(body))
         }
     }
     pub(crate) fn generate_transaction_id(&mut self) -> u32 {
@@ -150,28 +482,300 @@ impl Session {
         id
     }
 
+    /// The number of messages in the `Spool`, waiting for the session to
+    /// connect or for the broker to confirm them.
+    pub fn spooled(&self) -> usize {
+        self.state.spool.as_ref().map_or(0, SpoolFile::len)
+    }
+
+    /// The number of messages sent with `ReliablePublish` which the broker
+    /// has not confirmed yet.
+    pub fn unconfirmed(&self) -> usize {
+        let outstanding = &self.state.outstanding_receipts;
+        self.state
+            .unconfirmed
+            .pending(|receipt_id| outstanding.contains_key(receipt_id))
+            .count()
+    }
+
+    // The time by which the broker must confirm `frame` if it is to be sent
+    // reliably
+    pub(crate) fn reliable_deadline(&self, frame: &Frame) -> Option<Instant> {
+        let ReliablePublish(deadline) = self.config.reliable_publish?;
+        match frame.command {
+            Command::Send if frame.headers.get_transaction().is_none() => {
+                Some(Instant::now() + deadline)
+            }
+            _ => None,
+        }
+    }
+
     pub(crate) fn generate_receipt_id(&mut self) -> u32 {
         let id = self.state.next_receipt_id;
         self.state.next_receipt_id += 1;
         id
     }
+
+    // Sends `frame` with a new `receipt` header, returning a future which
+    // resolves once the broker has answered it.
+    pub(crate) fn send_with_receipt(&mut self, mut frame: Frame) -> ReceiptFuture {
+        let receipt_id = format!(
+            "{}/{}",
+            frame.command.as_str().to_lowercase(),
+            self.generate_receipt_id()
+        );
+        frame.headers.insert(Header::new("receipt", &receipt_id));
+        let (sender, receiver) = oneshot::channel();
+        let outstanding = OutstandingReceipt {
+            original_frame: frame.clone(),
+            notify: Some(sender),
+        };
+        self.state
+            .outstanding_receipts
+            .insert(receipt_id, outstanding);
+        self.send_frame(frame);
+        if !matches!(self.stream, StreamState::Connected(_)) {
+            // The frame was dropped, so no receipt will ever arrive
+            self.fail_receipts();
+        }
+        ReceiptFuture { receiver }
+    }
 }
 // *** Internal API ***
 impl Session {
-    fn _send(&mut self, tx: Transmission) -> Result<()> {
-        if let StreamState::Connected(ref mut st) = self.stream {
-            st.start_send(tx)?;
-            st.poll_complete()?;
+    // Hands queued transmissions to the connection in order, stopping at the
+    // first one which cannot be written yet.
+    fn flush_outbound(&mut self) -> Result<()> {
+        let st = match self.stream {
+            StreamState::Connected(ref mut st) => st,
+            _ => return Ok(()),
+        };
+        while let Some(outbound) = self.outbound.pop_front() {
+            let unsent = match outbound {
+                Outbound::Transmission(tx) => match st.start_send(tx)? {
+                    AsyncSink::Ready => None,
+                    AsyncSink::NotReady(tx) => Some(Outbound::Transmission(tx)),
+                },
+                Outbound::Streamed(mut body) => match body.poll_send(st)? {
+                    Async::Ready(()) => None,
+                    Async::NotReady => Some(Outbound::Streamed(body)),
+                },
+            };
+            if let Some(outbound) = unsent {
+                self.outbound.push_front(outbound);
+                break;
+            }
+        }
+        st.poll_complete()?;
+        Ok(())
+    }
+    fn _send(&mut self, outbound: Outbound) -> Result<()> {
+        if let StreamState::Connected(_) = self.stream {
+            self.outbound.push_back(outbound);
+            self.flush_outbound()?;
         } else {
-            warn!("sending {:?} whilst disconnected", tx);
+            warn!("sending {:?} whilst disconnected", outbound);
         }
         Ok(())
     }
-    fn send(&mut self, tx: Transmission) {
-        if let Err(e) = self._send(tx) {
+    fn send_outbound(&mut self, outbound: Outbound) {
+        if let Err(e) = self._send(outbound) {
             self.on_disconnect(DisconnectionReason::SendFailed(e));
         }
     }
+    fn send(&mut self, tx: Transmission) -> bool {
+        let tx = match tx {
+            CompleteFrame(mut frame) => {
+                if !self.prepare_frame(&mut frame) {
+                    return false;
+                }
+                self.record_transactional(&frame);
+                if self.spool_frame(&mut frame) {
+                    return true;
+                }
+                CompleteFrame(frame)
+            }
+            tx => tx,
+        };
+        self.send_outbound(Outbound::Transmission(tx));
+        true
+    }
+    // Appends a message to the spool rather than sending it until the broker
+    // has answered CONNECT, from which point the spool is sent. While older
+    // messages are being sent from the spool, the message is appended and sent
+    // after them. Returns false if the frame is to be sent.
+    fn spool_frame(&mut self, frame: &mut Frame) -> bool {
+        let spool = match self.state.spool {
+            Some(ref mut spool) => spool,
+            None => return false,
+        };
+        let connected = self.stomp_connected;
+        if !matches!(frame.command, Command::Send)
+            || frame.headers.get_transaction().is_some()
+            || (connected && spool.is_empty())
+        {
+            return false;
+        }
+        if let Err(e) = spool.append(frame) {
+            warn!("Could not spool a message: {}", e);
+            self.abandon_receipt(frame, Error::Disconnected);
+            self.events.push(SessionEvent::FrameRejected {
+                frame: frame.clone(),
+                error: Error::Io(e),
+            });
+            return true;
+        }
+        if connected {
+            self.request_spool_receipt(frame);
+        }
+        !connected
+    }
+    // Adds messages and acknowledgements sent within a transaction started by
+    // `begin_transaction` to that transaction's log.
+    fn record_transactional(&mut self, frame: &Frame) {
+        let log = match frame.headers.get_transaction() {
+            Some(header::Transaction(id)) => match self.state.transactions.get_mut(id) {
+                Some(log) => log,
+                None => return,
+            },
+            None => return,
+        };
+        match frame.command {
+            Command::Send => log.messages.push(frame.clone()),
+            Command::Ack | Command::Nack => log.acks.push(frame.clone()),
+            _ => {}
+        }
+    }
+    // Applies the configured duplicate header policy to a frame about to be
+    // sent, returning false if the frame was rejected instead.
+    fn prepare_frame(&mut self, frame: &mut Frame) -> bool {
+        if !interceptor::before_send(&self.config.interceptors, frame) {
+            debug!("{} frame dropped by an interceptor", frame.command);
+            let reason = "frame was dropped by an interceptor".to_owned();
+            self.abandon_receipt(frame, Error::InvalidUsage(reason));
+            return false;
+        }
+        let invalid_destination =
+            frame
+                .headers
+                .get_destination()
+                .and_then(|header::Destination(destination)| {
+                    Destination::from(destination).validate().err()
+                });
+        let reason = match invalid_destination {
+            Some(e) => format!("{} frame has an {}", frame.command, e),
+            None => match frame.headers.apply_policy(self.config.header_policy) {
+                Ok(()) => return true,
+                Err(key) => format!("{} frame has more than one '{}' header", frame.command, key),
+            },
+        };
+        warn!("Not sending frame: {}", reason);
+        self.abandon_receipt(frame, Error::InvalidUsage(reason.clone()));
+        self.events.push(SessionEvent::FrameRejected {
+            frame: frame.clone(),
+            error: Error::InvalidUsage(reason),
+        });
+        false
+    }
+    // Stops waiting for the receipt requested by a frame which is not sent.
+    fn abandon_receipt(&mut self, frame: &Frame, error: Error) {
+        if let Some(header::Receipt(receipt_id)) = frame.headers.get_receipt() {
+            if let Some(mut outstanding) = self.state.outstanding_receipts.remove(receipt_id) {
+                outstanding.resolve(Err(error));
+            }
+        }
+    }
+    fn poll_transactional(&mut self) {
+        for job in ::std::mem::take(&mut self.transactional_jobs) {
+            if let Some(job) = self.step_transactional(job) {
+                self.transactional_jobs.push(job);
+            }
+        }
+    }
+    // Advances a `transactional` job as far as possible, returning it if it
+    // has not finished yet.
+    fn step_transactional(&mut self, mut job: TransactionalJob) -> Option<TransactionalJob> {
+        loop {
+            let error = match job.state {
+                JobState::Ready => {
+                    if !matches!(self.stream, StreamState::Connected(_)) {
+                        return Some(job);
+                    }
+                    job.attempts += 1;
+                    let id = format!("tx/{}", self.generate_transaction_id());
+                    self.state
+                        .transactions
+                        .insert(id.clone(), TransactionLog::default());
+                    self.send_frame(Frame::begin(&id));
+                    let scope = TransactionScope::new(&id);
+                    let work = (job.work)(scope.clone());
+                    job.state = JobState::Working(scope, work);
+                    continue;
+                }
+                JobState::Working(ref scope, ref mut work) => {
+                    for frame in scope.take_queued() {
+                        self.send_frame(frame);
+                    }
+                    let id = scope.id().to_owned();
+                    if !matches!(self.stream, StreamState::Connected(_)) {
+                        // The broker drops the transaction with the connection
+                        self.state.transactions.remove(&id);
+                        Error::Disconnected
+                    } else {
+                        match work.poll() {
+                            Ok(Async::NotReady) => return Some(job),
+                            Ok(Async::Ready(())) => {
+                                for frame in scope.take_queued() {
+                                    self.send_frame(frame);
+                                }
+                                self.state.transactions.remove(&id);
+                                let receipt = self.send_with_receipt(Frame::commit(&id));
+                                job.state = JobState::Committing(receipt);
+                                continue;
+                            }
+                            Err(e) => {
+                                debug!("Aborting transaction '{}': {}", id, e);
+                                self.send_frame(Frame::abort(&id));
+                                self.state.transactions.remove(&id);
+                                job.finish(Err(e));
+                                return None;
+                            }
+                        }
+                    }
+                }
+                JobState::Committing(ref mut receipt) => match receipt.poll() {
+                    Ok(Async::Ready(_)) => {
+                        let attempts = job.attempts;
+                        job.finish(Ok(attempts));
+                        return None;
+                    }
+                    Ok(Async::NotReady) => return Some(job),
+                    Err(e) => e,
+                },
+                JobState::BackingOff(ref mut timeout) => match timeout.poll() {
+                    Ok(Async::Ready(())) => {
+                        job.state = JobState::Ready;
+                        continue;
+                    }
+                    Ok(Async::NotReady) => return Some(job),
+                    Err(e) => e.into(),
+                },
+            };
+            if !job.policy.should_retry(job.attempts, &error) {
+                job.finish(Err(error));
+                return None;
+            }
+            let backoff = job.policy.backoff(job.attempts);
+            warn!("Transaction failed ({}), retrying in {:?}", error, backoff);
+            match Timeout::new(backoff, &self.hdl) {
+                Ok(timeout) => job.state = JobState::BackingOff(timeout),
+                Err(e) => {
+                    job.finish(Err(e.into()));
+                    return None;
+                }
+            }
+        }
+    }
     fn register_tx_heartbeat_timeout(&mut self) -> Result<()> {
         use std::time::Duration;
         if self.state.tx_heartbeat_ms.is_none() {
@@ -221,6 +825,29 @@ impl Session {
         Ok(())
     }
 
+    // Data received from the broker but left unread while reads are paused
+    // shows it is alive, so the deadline for hearing from it is extended while
+    // there is some, for a few heartbeat periods at most
+    fn on_reads_paused(&mut self) -> Result<()> {
+        let paused_since = *self
+            .state
+            .reads_paused_since
+            .get_or_insert_with(Instant::now);
+        let rx_heartbeat_ms = match self.state.rx_heartbeat_ms {
+            Some(rx_heartbeat_ms) => u64::from(rx_heartbeat_ms),
+            None => return Ok(()),
+        };
+        let unread = match self.stream {
+            StreamState::Connected(ref fr) => fr.codec().has_unread(),
+            _ => false,
+        };
+        let limit = Duration::from_millis(rx_heartbeat_ms * MAX_PAUSED_HEARTBEAT_PERIODS);
+        if unread && paused_since.elapsed() < limit {
+            self.register_rx_heartbeat_timeout()?;
+        }
+        Ok(())
+    }
+
     fn reply_to_heartbeat(&mut self) -> Result<()> {
         debug!("Sending heartbeat");
         self.send(HeartBeat);
@@ -234,11 +861,37 @@ impl Session {
             let _ = strm.get_mut().shutdown(::std::net::Shutdown::Both);
         }
         self.stream = StreamState::Failed;
+        self.stomp_connected = false;
+        self.outbound.clear();
+        self.inbound_body = None;
+        self.fail_receipts();
+        self.fail_requests();
+        self.state.delayed_nacks.clear();
+        self.state.dead_letters.clear();
+        if let Some(ref mut spool) = self.state.spool {
+            spool.disconnected();
+        }
+        for consumer in self.state.consumers.values_mut() {
+            consumer.disconnected();
+        }
         self.state.tx_heartbeat_timeout = None;
         self.state.rx_heartbeat_timeout = None;
+        self.state.reads_paused_since = None;
+    }
+    // Fails every `ReceiptFuture` still waiting, as their receipts can no
+    // longer arrive.
+    fn fail_receipts(&mut self) {
+        self.state.outstanding_receipts.retain(|_, outstanding| {
+            if outstanding.notify.is_none() {
+                return true;
+            }
+            outstanding.resolve(Err(Error::Disconnected));
+            false
+        });
     }
     fn on_stream_ready(&mut self) {
         debug!("Stream ready!");
+        self.stomp_connected = false;
         // Add credentials to the header list if specified
         match self.config.credentials.clone() {
             // TODO: Refactor to avoid clone
@@ -248,8 +901,8 @@ impl Session {
                     credentials.login, credentials.passcode
                 );
                 let headers = &mut self.config.headers;
-                headers.push(Header::new("login", &credentials.login));
-                headers.push(Header::new("passcode", &credentials.passcode));
+                headers.insert(Header::new_raw("login", credentials.login));
+                headers.insert(Header::new_raw("passcode", credentials.passcode));
             }
             None => debug!("No credentials supplied."),
         }
@@ -259,20 +912,67 @@ impl Session {
         debug!("Using heartbeat: {},{}", client_tx_ms, client_rx_ms);
         self.config
             .headers
-            .push(Header::new("heart-beat", heart_beat_string.as_ref()));
+            .insert(Header::new("heart-beat", heart_beat_string.as_ref()));
 
         let connect_frame = Frame {
             command: Command::Connect,
             headers: self.config.headers.clone(), /* Cloned to allow this to be re-used */
             body: Vec::new(),
         };
+        // CONNECT headers are not escaped, so such characters would end the
+        // header, or the frame, early. Values may contain colons, as only the
+        // first one of a header separates its key from its value.
+        let unsafe_char = |c: char| matches!(c, '\r' | '\n' | '\0');
+        if let Some(header) = connect_frame.headers.iter().find(|h| {
+            h.get_key().contains(|c| unsafe_char(c) || c == ':')
+                || h.get_value().contains(unsafe_char)
+        }) {
+            let reason = format!(
+                "the '{}' header of the CONNECT frame contains a line break or NUL, \
+                 or a colon in its key",
+                header.get_key()
+            );
+            warn!("Not connecting: {}", reason);
+            let error = Error::InvalidUsage(reason);
+            return self.on_disconnect(DisconnectionReason::ConnectFailed(error));
+        }
 
         self.send_frame(connect_frame);
     }
     fn on_message(&mut self, frame: Frame) {
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let frame = {
+            let mut frame = frame;
+            let limit = self.config.limits.max_frame_size;
+            if let Err(e) = crate::compression::decompress_frame(&mut frame, limit) {
+                warn!(
+                    "Delivering message as received, decompression failed: {}",
+                    e
+                );
+            }
+            frame
+        };
+        if self.dropped(&frame) {
+            return;
+        }
         let mut sub_data = None;
         if let Some(header::Subscription(sub_id)) = frame.headers.get_subscription() {
-            if let Some(ref sub) = self.state.subscriptions.get(sub_id) {
+            if self.state.reply_subscription.as_deref() == Some(sub_id) {
+                return self.on_reply(frame);
+            }
+            if self.state.request_handlers.contains_key(sub_id) {
+                let sub_id = sub_id.to_owned();
+                return self.on_request(&sub_id, frame);
+            }
+            if let Some(consumer) = self.state.consumers.get_mut(sub_id) {
+                if consumer.is_stopping() {
+                    debug!("Ignoring a message arriving at a stopping consumer");
+                } else {
+                    consumer.push(frame);
+                }
+                return;
+            }
+            if let Some(sub) = self.state.subscriptions.get(sub_id) {
                 sub_data = Some((sub.destination.clone(), sub.ack_mode));
             }
         }
@@ -287,6 +987,343 @@ impl Session {
         }
     }
 
+    // Whether a message is dropped by its subscription's filter, or as a
+    // duplicate. Dropped messages are acknowledged if the broker expects each
+    // to be. A cumulative ACK in `AckMode::Client` would also acknowledge the
+    // messages received before, which the application may not have handled,
+    // so those are left to the application's next ACK.
+    fn dropped(&mut self, frame: &Frame) -> bool {
+        let sub = match frame
+            .headers
+            .get_subscription()
+            .and_then(|header::Subscription(sub_id)| self.state.subscriptions.get_mut(sub_id))
+        {
+            Some(sub) => sub,
+            None => return false,
+        };
+        if sub
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(&frame.headers))
+        {
+            debug!("Dropping a message not matching its subscription's selector");
+        } else if sub
+            .seen
+            .as_mut()
+            .is_some_and(|seen| seen.is_duplicate(&frame.headers, Instant::now()))
+        {
+            debug!("Dropping a duplicate message");
+        } else {
+            // Messages are acknowledged on delivery
+            if let (AckMode::Auto, Some(seen)) = (sub.ack_mode, sub.seen.as_mut()) {
+                seen.record(&frame.headers, Instant::now());
+            }
+            return false;
+        }
+        let ack_mode = sub.ack_mode;
+        if let AckMode::ClientIndividual = ack_mode {
+            if let Some(ack_frame) = Frame::acknowledgement(frame, AckOrNack::Ack) {
+                self.send_frame(ack_frame);
+            }
+        }
+        true
+    }
+
+    // Lets deduplication drop the redeliveries of a handled message
+    fn remember_handled(&mut self, frame: &Frame) {
+        if let Some(seen) = frame
+            .headers
+            .get_subscription()
+            .and_then(|header::Subscription(sub_id)| self.state.subscriptions.get_mut(sub_id))
+            .and_then(|sub| sub.seen.as_mut())
+        {
+            seen.record(&frame.headers, Instant::now());
+        }
+    }
+
+    fn on_reply(&mut self, frame: Frame) {
+        let request = match frame.headers.get_correlation_id() {
+            Some(header::CorrelationId(id)) => self.state.pending_requests.remove(id),
+            None => None,
+        };
+        match request {
+            Some(request) => request.resolve(Ok(frame)),
+            None => debug!("Discarding a reply to no pending request"),
+        }
+    }
+
+    fn on_request(&mut self, sub_id: &str, frame: Frame) {
+        let reply = match self.state.request_handlers.get_mut(sub_id) {
+            Some(handler) => handler(&frame),
+            None => return,
+        };
+        if let Some(reply) = reply {
+            self.send_frame(reply);
+        }
+        let acknowledge = match self.state.subscriptions.get(sub_id) {
+            Some(sub) => !matches!(sub.ack_mode, AckMode::Auto),
+            None => false,
+        };
+        if acknowledge {
+            self.acknowledge_frame(&frame, AckOrNack::Ack);
+        }
+    }
+
+    fn poll_requests(&mut self) {
+        let mut expired = Vec::new();
+        for (id, request) in self.state.pending_requests.iter_mut() {
+            match request.poll_expired() {
+                Ok(false) => {}
+                Ok(true) => expired.push((id.clone(), Error::RequestTimeout(id.clone()))),
+                Err(e) => expired.push((id.clone(), e)),
+            }
+        }
+        for (id, error) in expired {
+            if let Some(request) = self.state.pending_requests.remove(&id) {
+                request.resolve(Err(error));
+            }
+        }
+    }
+
+    // Gives up on the reliably sent messages whose deadline has passed.
+    fn poll_unconfirmed(&mut self) {
+        let outstanding = &self.state.outstanding_receipts;
+        let expired = self.state.unconfirmed.expire(Instant::now(), |receipt_id| {
+            outstanding.contains_key(receipt_id)
+        });
+        for receipt_id in expired {
+            if let Some(outstanding) = self.state.outstanding_receipts.remove(&receipt_id) {
+                warn!("No receipt arrived in time for message '{}'", receipt_id);
+                self.events.push(SessionEvent::SendFailed {
+                    frame: outstanding.original_frame,
+                    error: Error::ReceiptTimeout(receipt_id),
+                });
+            }
+        }
+        self.state.unconfirmed.schedule(&self.hdl);
+    }
+
+    // Sends again the reliably sent messages which the broker has not
+    // confirmed, e.g. as they were lost with the previous connection, apart
+    // from those still in the spool.
+    fn resend_unconfirmed(&mut self, spooled: &[Frame]) {
+        let spooled: HashSet<&str> = spooled
+            .iter()
+            .filter_map(|frame| frame.headers.get_receipt())
+            .map(|header::Receipt(receipt_id)| receipt_id)
+            .collect();
+        let outstanding = &self.state.outstanding_receipts;
+        let frames: Vec<Frame> = self
+            .state
+            .unconfirmed
+            .pending(|receipt_id| {
+                outstanding.contains_key(receipt_id) && !spooled.contains(receipt_id)
+            })
+            .map(|receipt_id| outstanding[receipt_id].original_frame.clone())
+            .collect();
+        if !frames.is_empty() {
+            info!("Resending {} unconfirmed messages", frames.len());
+        }
+        for frame in frames {
+            self.send_outbound(Outbound::Transmission(CompleteFrame(frame)));
+        }
+    }
+
+    // The spooled messages which the broker has not confirmed, oldest first
+    fn pending_spooled(&mut self) -> Vec<Frame> {
+        let pending = match self.state.spool {
+            Some(ref mut spool) => spool.pending(),
+            None => return Vec::new(),
+        };
+        match pending {
+            Ok(frames) => frames,
+            Err(e) => {
+                warn!("Could not read the spooled messages: {}", e);
+                self.events.push(SessionEvent::SpoolFailed(Error::Io(e)));
+                Vec::new()
+            }
+        }
+    }
+
+    // Sends spooled messages in order, each requesting a receipt. They stay
+    // in the spool until the broker has confirmed them, so messages may be
+    // sent twice but are not lost.
+    fn drain_spool(&mut self, frames: Vec<Frame>) {
+        if !frames.is_empty() {
+            info!("Sending {} spooled messages", frames.len());
+        }
+        for mut frame in frames {
+            self.request_spool_receipt(&mut frame);
+            self.send_outbound(Outbound::Transmission(CompleteFrame(frame)));
+        }
+    }
+
+    // Makes a spooled message request a receipt, unless it already requests
+    // one this session is waiting for, and notes that it is being sent
+    fn request_spool_receipt(&mut self, frame: &mut Frame) {
+        let receipt_id = match frame.headers.get_receipt() {
+            Some(header::Receipt(receipt_id))
+                if self.state.outstanding_receipts.contains_key(receipt_id) =>
+            {
+                receipt_id.to_owned()
+            }
+            _ => {
+                let receipt_id = format!("spool/{}", self.generate_receipt_id());
+                frame.headers.insert(Header::new("receipt", &receipt_id));
+                receipt_id
+            }
+        };
+        if let Some(ref mut spool) = self.state.spool {
+            spool.sent(receipt_id);
+        }
+    }
+
+    // Notes that the broker has processed the frame which requested the
+    // receipt `receipt_id`, which may be a spooled message
+    fn confirm_spooled(&mut self, receipt_id: &str) {
+        let confirmed = match self.state.spool {
+            Some(ref mut spool) => spool.confirm(receipt_id),
+            None => return,
+        };
+        if let Err(e) = confirmed {
+            warn!("Could not empty the spool: {}", e);
+            self.events.push(SessionEvent::SpoolFailed(Error::Io(e)));
+        }
+    }
+
+    // Runs the handlers of consumers, acknowledging the messages they have
+    // handled, and ends the subscriptions of stopped consumers which have
+    // finished.
+    fn poll_consumers(&mut self) {
+        let full = self.consumers_full();
+        let mut outcomes = Vec::new();
+        let mut stopped = Vec::new();
+        for (sub_id, consumer) in self.state.consumers.iter_mut() {
+            outcomes.extend(consumer.poll());
+            if consumer.is_stopping() && consumer.is_idle() {
+                stopped.push(sub_id.clone());
+            }
+        }
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Handled(frame) => self.acknowledge_frame(&frame, AckOrNack::Ack),
+                Outcome::Failed(frame, reason) => self.message_failed(&frame, &reason),
+            }
+        }
+        for sub_id in stopped {
+            if let Some(consumer) = self.state.consumers.remove(&sub_id) {
+                self.unsubscribe(&sub_id);
+                consumer.stopped();
+            }
+        }
+        if full && !self.consumers_full() {
+            // Frames can be read again
+            task::current().notify();
+        }
+    }
+
+    // Sends the NACKs of failed messages whose backoff has elapsed.
+    fn poll_delayed_nacks(&mut self) {
+        let mut due = Vec::new();
+        self.state
+            .delayed_nacks
+            .retain_mut(|(timeout, nack_frame)| match timeout.poll() {
+                Ok(Async::NotReady) => true,
+                _ => {
+                    due.push(nack_frame.clone());
+                    false
+                }
+            });
+        for nack_frame in due {
+            self.send_frame(nack_frame);
+        }
+    }
+
+    // Acknowledges failed messages once their dead letter is confirmed. A
+    // refused dead letter, or one lost with the connection, leaves its
+    // message to be redelivered and to fail again.
+    fn poll_dead_letters(&mut self) {
+        let mut done = Vec::new();
+        self.state
+            .dead_letters
+            .retain_mut(|(receipt, frame)| match receipt.poll() {
+                Ok(Async::NotReady) => true,
+                result => {
+                    done.push((result.map(|_| ()), frame.clone()));
+                    false
+                }
+            });
+        for (result, frame) in done {
+            match result {
+                Ok(()) => self.acknowledge_frame(&frame, AckOrNack::Ack),
+                Err(Error::Disconnected) => {}
+                Err(e) => {
+                    warn!("NACKing a message whose dead letter was refused: {}", e);
+                    self.acknowledge_frame(&frame, AckOrNack::Nack);
+                }
+            }
+        }
+    }
+
+    // Fails every request still waiting for a reply. The reply subscription
+    // is started again by the next request.
+    fn fail_requests(&mut self) {
+        for (_, request) in self.state.pending_requests.drain() {
+            request.resolve(Err(Error::Disconnected));
+        }
+        if let Some(id) = self.state.reply_subscription.take() {
+            self.state.subscriptions.remove(&id);
+        }
+    }
+
+    fn on_streamed_message(&mut self, frame: Frame) {
+        if self.dropped(&frame) {
+            self.inbound_body = Some(BodySender::discard());
+            return;
+        }
+        let content_length = match frame.headers.get_content_length() {
+            Some(header::ContentLength(length)) => length as usize,
+            None => 0,
+        };
+        let mut sub_data = None;
+        if let Some(header::Subscription(sub_id)) = frame.headers.get_subscription() {
+            if let Some(sub) = self.state.subscriptions.get(sub_id) {
+                sub_data = Some((sub.destination.clone(), sub.ack_mode));
+            }
+        }
+        if let Some((destination, ack_mode)) = sub_data {
+            let (sender, body) = BodyReader::new(content_length);
+            self.inbound_body = Some(sender);
+            self.events.push(SessionEvent::StreamedMessage {
+                destination,
+                ack_mode,
+                frame,
+                body,
+            });
+        } else {
+            warn!("Discarding the body of a streamed message without subscription");
+            self.inbound_body = Some(BodySender::discard());
+            self.events.push(SessionEvent::SubscriptionlessFrame(frame));
+        }
+    }
+
+    // Whether frames can be read, which waits for the reader of the body
+    // being streamed and for consumers with a full backlog.
+    fn inbound_ready(&mut self) -> bool {
+        self.inbound_body_ready() && !self.consumers_full()
+    }
+
+    fn consumers_full(&self) -> bool {
+        self.state.consumers.values().any(Consumer::is_full)
+    }
+
+    // Whether the reader of the body being streamed, if any, can take more.
+    fn inbound_body_ready(&mut self) -> bool {
+        self.inbound_body
+            .as_mut()
+            .is_none_or(|body| body.poll_ready())
+    }
+
     fn on_connected_frame_received(&mut self, connected_frame: Frame) -> Result<()> {
         // The Client's requested tx/rx HeartBeat timeouts
         let connection::HeartBeat(client_tx_ms, client_rx_ms) = self.config.heartbeat;
@@ -307,6 +1344,12 @@ impl Session {
         self.register_rx_heartbeat_timeout()?;
 
         self.events.push(SessionEvent::Connected);
+        // The spooled messages were all sent after the unconfirmed messages
+        // which are not in the spool, so this keeps the order they were sent in
+        let spooled = self.pending_spooled();
+        self.resend_unconfirmed(&spooled);
+        self.drain_spool(spooled);
+        self.stomp_connected = true;
 
         Ok(())
     }
@@ -319,10 +1362,12 @@ impl Session {
             }
         };
         if let Some(receipt_id) = receipt_id {
+            self.confirm_spooled(&receipt_id);
             if receipt_id == "msg/disconnect" {
                 self.on_disconnect(DisconnectionReason::Requested);
             }
-            if let Some(entry) = self.state.outstanding_receipts.remove(&receipt_id) {
+            if let Some(mut entry) = self.state.outstanding_receipts.remove(&receipt_id) {
+                entry.resolve(Ok(frame.clone()));
                 let original_frame = entry.original_frame;
                 self.events.push(SessionEvent::Receipt {
                     id: receipt_id,
@@ -333,15 +1378,22 @@ impl Session {
         }
     }
 
-    fn poll_stream_complete(&mut self) {
-        let res = {
-            if let StreamState::Connected(ref mut fr) = self.stream {
-                fr.poll_complete()
-            } else {
-                Ok(Async::NotReady)
+    fn on_error_frame(&mut self, frame: Frame) {
+        let mut error = BrokerError::new(frame);
+        if let Some(ref receipt_id) = error.receipt_id {
+            // A refused message would be refused again
+            self.confirm_spooled(receipt_id);
+            if let Some(mut entry) = self.state.outstanding_receipts.remove(receipt_id) {
+                entry.resolve(Err(error.clone().into()));
+                error.original = Some(entry.original_frame);
             }
-        };
-        if let Err(e) = res {
+        }
+        warn!("{}", error);
+        self.events.push(SessionEvent::ErrorFrame(error));
+    }
+
+    fn poll_stream_complete(&mut self) {
+        if let Err(e) = self.flush_outbound() {
             self.on_disconnect(DisconnectionReason::SendFailed(e));
         }
     }
@@ -369,7 +1421,10 @@ impl Session {
                 },
                 Connecting(mut tsn) => match tsn.poll() {
                     Ok(Async::Ready(s)) => {
-                        let fr = Framed::new(s, Codec);
+                        let codec = Codec::with_limits(self.config.limits)
+                            .with_stream_threshold(self.config.stream_threshold)
+                            .with_decode_mode(self.config.header_decoding);
+                        let fr = Framed::new(s, codec);
                         self.stream = Connected(fr);
                         self.on_stream_ready();
                     }
@@ -378,7 +1433,7 @@ impl Session {
                         return Async::NotReady;
                     }
                     Err(e) => {
-                        self.on_disconnect(DisconnectionReason::ConnectFailed(e));
+                        self.on_disconnect(DisconnectionReason::ConnectFailed(e.into()));
                         return Async::NotReady;
                     }
                 },
@@ -391,27 +1446,81 @@ impl Session {
 }
 #[derive(Debug)]
 pub enum DisconnectionReason {
-    RecvFailed(::std::io::Error),
-    ConnectFailed(::std::io::Error),
-    SendFailed(::std::io::Error),
+    RecvFailed(Error),
+    ConnectFailed(Error),
+    SendFailed(Error),
     ClosedByOtherSide,
     HeartbeatTimeout,
     Requested,
 }
+
+impl DisconnectionReason {
+    /// The error that caused the disconnection, if it was not requested.
+    pub fn into_error(self) -> Option<Error> {
+        match self {
+            DisconnectionReason::RecvFailed(e)
+            | DisconnectionReason::ConnectFailed(e)
+            | DisconnectionReason::SendFailed(e) => Some(e),
+            DisconnectionReason::ClosedByOtherSide => {
+                Some(Error::Io(::std::io::ErrorKind::ConnectionAborted.into()))
+            }
+            DisconnectionReason::HeartbeatTimeout => Some(Error::HeartbeatTimeout),
+            DisconnectionReason::Requested => None,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectionReason::RecvFailed(ref e) => write!(f, "receive failed: {}", e),
+            DisconnectionReason::ConnectFailed(ref e) => write!(f, "connect failed: {}", e),
+            DisconnectionReason::SendFailed(ref e) => write!(f, "send failed: {}", e),
+            DisconnectionReason::ClosedByOtherSide => write!(f, "connection closed by broker"),
+            DisconnectionReason::HeartbeatTimeout => write!(f, "heart-beat timeout"),
+            DisconnectionReason::Requested => write!(f, "disconnect requested"),
+        }
+    }
+}
 pub enum SessionEvent {
     Connected,
-    ErrorFrame(Frame),
+    ErrorFrame(BrokerError),
+    /// The broker processed `original`. A message sent with `message_stream`
+    /// is kept without its body or `content-length` header.
     Receipt {
         id: String,
         original: Frame,
         receipt: Frame,
     },
     Message {
-        destination: String,
+        destination: Destination,
         ack_mode: AckMode,
         frame: Frame,
     },
+    /// A message whose body is delivered through `body` as it arrives. Its
+    /// `frame` has an empty body.
+    StreamedMessage {
+        destination: Destination,
+        ack_mode: AckMode,
+        frame: Frame,
+        body: BodyReader,
+    },
     SubscriptionlessFrame(Frame),
+    /// A frame was not sent because it was invalid, or could not be spooled.
+    FrameRejected {
+        frame: Frame,
+        error: Error,
+    },
+    /// The `Spool` could not be read or emptied. Its messages are not sent if
+    /// it could not be read, and may be sent again if it could not be
+    /// emptied.
+    SpoolFailed(Error),
+    /// A message sent with `ReliablePublish` was not confirmed before its
+    /// deadline, and will not be sent again.
+    SendFailed {
+        frame: Frame,
+        error: Error,
+    },
     UnknownFrame(Frame),
     Disconnected(DisconnectionReason),
 }
@@ -424,35 +1533,74 @@ pub struct Session {
     config: SessionConfig,
     pub(crate) state: SessionState,
     stream: StreamState,
+    // Whether the broker has answered the CONNECT frame sent on the current
+    // connection
+    stomp_connected: bool,
     hdl: Handle,
     events: Vec<SessionEvent>,
+    outbound: VecDeque<Outbound>,
+    inbound_body: Option<BodySender>,
+    transactional_jobs: Vec<TransactionalJob>,
 }
 impl Stream for Session {
     type Item = SessionEvent;
-    type Error = ::std::io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         use crate::frame::Transmission::*;
 
-        while let Async::Ready(Some(val)) = self.poll_stream() {
+        while self.inbound_ready() {
+            let val = match self.poll_stream() {
+                Async::Ready(Some(val)) => val,
+                _ => break,
+            };
             match val {
                 HeartBeat => {
                     debug!("Received heartbeat.");
                     self.on_recv_data()?;
                 }
-                CompleteFrame(frame) => {
+                CompleteFrame(mut frame) => {
                     debug!("Received frame: {:?}", frame);
                     self.on_recv_data()?;
+                    if !interceptor::before_receive(&self.config.interceptors, &mut frame) {
+                        debug!("{} frame dropped by an interceptor", frame.command);
+                        continue;
+                    }
                     match frame.command {
-                        Command::Error => self.events.push(SessionEvent::ErrorFrame(frame)),
+                        Command::Error => self.on_error_frame(frame),
                         Command::Receipt => self.handle_receipt(frame),
                         Command::Connected => self.on_connected_frame_received(frame)?,
                         Command::Message => self.on_message(frame),
                         _ => self.events.push(SessionEvent::UnknownFrame(frame)),
                     };
                 }
+                FrameHead(mut frame) => {
+                    debug!("Received frame head: {:?}", frame);
+                    self.on_recv_data()?;
+                    if !interceptor::before_receive(&self.config.interceptors, &mut frame) {
+                        debug!("{} frame dropped by an interceptor", frame.command);
+                        self.inbound_body = Some(BodySender::discard());
+                        continue;
+                    }
+                    self.on_streamed_message(frame);
+                }
+                BodyChunk(chunk) => {
+                    self.on_recv_data()?;
+                    if let Some(ref mut body) = self.inbound_body {
+                        body.push(chunk);
+                    }
+                }
+                BodyEnd => {
+                    self.on_recv_data()?;
+                    self.inbound_body = None;
+                }
             }
         }
+        if self.inbound_ready() {
+            self.state.reads_paused_since = None;
+        } else {
+            self.on_reads_paused()?;
+        }
 
         let rxh = self
             .state
@@ -476,6 +1624,12 @@ impl Stream for Session {
             self.reply_to_heartbeat()?;
         }
 
+        self.poll_requests();
+        self.poll_consumers();
+        self.poll_delayed_nacks();
+        self.poll_dead_letters();
+        self.poll_unconfirmed();
+        self.poll_transactional();
         self.poll_stream_complete();
 
         if !self.events.is_empty() {
@@ -489,3 +1643,150 @@ impl Stream for Session {
         }
     }
 }
+
+#[test]
+fn refuse_header_injection_in_connect_frame() {
+    use crate::connection::Credentials;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| Client::accept(&listener).closed());
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(Credentials("guest", "guest\nclient-id:admin"))
+        .start(core.handle())
+        .unwrap();
+    let events = run_until(&mut core, &mut session, |_, events| !events.is_empty());
+    match events[0] {
+        SessionEvent::Disconnected(DisconnectionReason::ConnectFailed(Error::InvalidUsage(_))) => {}
+        _ => panic!("expected the connection to be refused"),
+    }
+    broker.join().unwrap();
+}
+
+#[test]
+fn connect_with_colon_in_passcode() {
+    use crate::connection::Credentials;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, connected, header_value, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::accept(&listener);
+        let connect = client.read();
+        client.write("CONNECTED\nversion:1.2\n\n\0");
+        header_value(&connect, "passcode").to_owned()
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(Credentials("guest", "pass:word"))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+    assert_eq!(broker.join().unwrap(), "pass:word");
+}
+
+#[test]
+fn resume_and_destroy_durable_subscription() {
+    use crate::dialect::activemq::DurableSubscriptionName;
+    use crate::header::ClientId;
+    use crate::session_builder::SessionBuilder;
+    use crate::subscription::SubscriptionId;
+    use crate::test_broker::{broker, connected, header_value, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::accept(&listener);
+        let connect = client.read();
+        assert_eq!(header_value(&connect, "client-id"), "billing-1");
+        client.write("CONNECTED\nversion:1.2\n\n\0");
+        let mut durable_headers = Vec::new();
+        for command in &["SUBSCRIBE", "UNSUBSCRIBE", "SUBSCRIBE", "UNSUBSCRIBE"] {
+            let frame = client.read();
+            assert_eq!(frame.command.as_str(), *command);
+            assert_eq!(header_value(&frame, "id"), "invoices");
+            let durable = frame.headers.get_header("activemq.subscriptionName");
+            durable_headers.push(durable.map(|h| h.get_value().to_owned()));
+        }
+        durable_headers
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(ClientId("billing-1"))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+
+    let subscribe = |session: &mut Session| {
+        session
+            .subscription("/topic/invoices")
+            .with(SubscriptionId("invoices"))
+            .with(DurableSubscriptionName("invoices"))
+            .start()
+    };
+    let id = subscribe(&mut session);
+    assert_eq!(id, "invoices");
+    session.unsubscribe(&id);
+    subscribe(&mut session);
+    session.unsubscribe_with(&id, Unsubscribe::Destroy);
+    run_until(&mut core, &mut session, |_, _| broker.is_finished());
+
+    let invoices = Some("invoices".to_owned());
+    assert_eq!(
+        broker.join().unwrap(),
+        vec![invoices.clone(), None, invoices.clone(), invoices]
+    );
+}
+
+#[test]
+fn detect_silent_broker_while_reads_are_paused() {
+    use crate::connection::HeartBeat;
+    use crate::consumer::HandlerFuture;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, connected, header_value, run_until, Client};
+    use tokio_core::reactor::Core;
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::accept(&listener);
+        client.read();
+        client.write("CONNECTED\nversion:1.2\nheart-beat:50,0\n\n\0");
+        let subscribe = client.read();
+        let sub_id = header_value(&subscribe, "id").to_owned();
+        // The handler never finishes the first message, so the second fills
+        // the backlog and the third is left unread
+        let mut messages = String::new();
+        for message_id in &["1", "2", "3"] {
+            messages.push_str(&format!(
+                "MESSAGE\nsubscription:{}\nmessage-id:{}\ndestination:/queue/a\n\n\0",
+                sub_id, message_id
+            ));
+        }
+        client.write(&messages);
+        client
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(HeartBeat(0, 50))
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+    session
+        .subscription("/queue/a")
+        .consume(1, |_: &Frame| -> HandlerFuture {
+            Box::new(future::empty())
+        });
+
+    let events = run_until(&mut core, &mut session, |_, events| !events.is_empty());
+    match events[0] {
+        SessionEvent::Disconnected(DisconnectionReason::HeartbeatTimeout) => {}
+        _ => panic!("expected a heartbeat timeout"),
+    }
+    broker.join().unwrap();
+}
@@ -1,8 +1,15 @@
+use crate::codec::FrameLimits;
 use crate::connection::{HeartBeat, OwnedCredentials};
-use crate::header::{Header, HeaderList};
+use crate::error::Result;
+use crate::escape::DecodeMode;
+use crate::header::{DuplicateHeaderPolicy, Header, HeaderList};
+use crate::interceptor::Interceptors;
 use crate::option_setter::OptionSetter;
+use crate::publish::ReliablePublish;
+use crate::request::ReplyQueue;
 
 use crate::session::Session;
+use crate::spool::{Spool, SpoolFile};
 use std::io;
 use std::net::ToSocketAddrs;
 use tokio_core::net::TcpStream;
@@ -15,6 +22,14 @@ pub struct SessionConfig {
     pub credentials: Option<OwnedCredentials>,
     pub heartbeat: HeartBeat,
     pub headers: HeaderList,
+    pub limits: FrameLimits,
+    pub stream_threshold: Option<usize>,
+    pub header_policy: DuplicateHeaderPolicy,
+    pub header_decoding: DecodeMode,
+    pub(crate) interceptors: Interceptors,
+    pub reply_queue: Option<ReplyQueue>,
+    pub reliable_publish: Option<ReliablePublish>,
+    pub spool: Option<Spool>,
 }
 
 pub struct SessionBuilder {
@@ -33,23 +48,28 @@ impl SessionBuilder {
              "accept-version" => "1.2",
              "content-length" => "0"
             ],
+            limits: FrameLimits::default(),
+            stream_threshold: None,
+            header_policy: DuplicateHeaderPolicy::default(),
+            header_decoding: DecodeMode::default(),
+            interceptors: Vec::new(),
+            reply_queue: None,
+            reliable_publish: None,
+            spool: None,
         };
         SessionBuilder { config }
     }
 
     #[allow(dead_code)]
-    pub fn start(self, hdl: Handle) -> ::std::io::Result<Session> {
+    pub fn start(self, hdl: Handle) -> Result<Session> {
         let address = (&self.config.host as &str, self.config.port)
             .to_socket_addrs()?
-            .nth(0)
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::Other, "address provided resolved to nothing")
-            })?;
-        Ok(Session::new(
-            self.config,
-            TcpStream::connect(&address, &hdl),
-            hdl,
-        ))
+            .next()
+            .ok_or_else(|| io::Error::other("address provided resolved to nothing"))?;
+        let spool = self.config.spool.clone().map(SpoolFile::open).transpose()?;
+        let mut session = Session::new(self.config, TcpStream::connect(&address, &hdl), hdl);
+        session.state.spool = spool;
+        Ok(session)
     }
 
     #[allow(dead_code)]
@@ -0,0 +1,423 @@
+//! Store-and-forward of messages sent while disconnected. With a `Spool`,
+//! such messages are appended to a file, as they would be written to the
+//! connection, and sent in order once the session has connected again. The
+//! file survives restarts: its messages are sent after the next CONNECTED,
+//! and a message torn by a crash while it was being appended is discarded. A
+//! file which cannot be read back is left as it is, and the spool fails to
+//! open.
+use crate::codec::{Codec, FrameLimits};
+use crate::frame::{Frame, Transmission};
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tokio_io::codec::Decoder;
+
+/// Spools messages to the file at `path`, holding at most `max_messages`
+/// messages and `max_bytes` bytes. Messages which do not fit are rejected
+/// with a `SessionEvent::FrameRejected`. Each message is flushed to disk
+/// before `send` returns unless `sync` is false.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    pub path: PathBuf,
+    pub max_messages: usize,
+    pub max_bytes: u64,
+    pub sync: bool,
+}
+
+impl Spool {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Spool {
+        Spool {
+            path: path.into(),
+            max_messages: 100_000,
+            max_bytes: 256 * 1024 * 1024,
+            sync: true,
+        }
+    }
+
+    pub fn max_messages(mut self, max_messages: usize) -> Spool {
+        self.max_messages = max_messages;
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u64) -> Spool {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn sync(mut self, sync: bool) -> Spool {
+        self.sync = sync;
+        self
+    }
+}
+
+// The open spool file, and the messages it holds
+pub(crate) struct SpoolFile {
+    config: Spool,
+    file: File,
+    messages: usize,
+    bytes: u64,
+    // The receipt ids of the spooled messages sent on the current
+    // connection, oldest first
+    sent: VecDeque<String>,
+    // The number of spooled messages, oldest first, which the broker has
+    // confirmed
+    confirmed: usize,
+}
+
+impl SpoolFile {
+    // Opens the spool, creating it if needed, and discards any message torn
+    // by a crash
+    pub fn open(config: Spool) -> io::Result<SpoolFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&config.path)?;
+        let mut spool = SpoolFile {
+            config,
+            file,
+            messages: 0,
+            bytes: 0,
+            sent: VecDeque::new(),
+            confirmed: 0,
+        };
+        let mut frames = spool.frames()?;
+        if !frames.is_empty() {
+            info!(
+                "Recovered {} spooled messages from {}",
+                frames.len(),
+                spool.config.path.display()
+            );
+        }
+        // Their receipts were requested by a previous session, and new ones
+        // are requested when they are sent
+        if frames
+            .iter()
+            .any(|frame| frame.headers.get_receipt().is_some())
+        {
+            for frame in frames.iter_mut() {
+                frame.headers.remove("receipt");
+            }
+            spool.compact(&frames)?;
+        }
+        Ok(spool)
+    }
+
+    // The number of messages which the broker has not confirmed
+    pub fn len(&self) -> usize {
+        self.messages - self.confirmed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn append(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut encoded = BytesMut::new();
+        frame.write(&mut encoded);
+        if self.messages >= self.config.max_messages
+            || self.bytes + encoded.len() as u64 > self.config.max_bytes
+        {
+            return Err(io::Error::other("the spool is full"));
+        }
+        self.file.write_all(&encoded)?;
+        if self.config.sync {
+            self.file.sync_data()?;
+        }
+        self.messages += 1;
+        self.bytes += encoded.len() as u64;
+        Ok(())
+    }
+
+    // The spooled messages, oldest first. An incomplete message following the
+    // last complete one is truncated, while a corrupt message fails the read
+    // and is left in the file.
+    pub fn frames(&mut self) -> io::Result<Vec<Frame>> {
+        let mut contents = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut contents)?;
+        let length = contents.len() as u64;
+        let mut buffer = BytesMut::from(contents);
+        // Any message which fit in the spool can be read back
+        let max_bytes = self.config.max_bytes.min(usize::MAX as u64) as usize;
+        let limits = FrameLimits {
+            max_frame_size: max_bytes,
+            max_headers: usize::MAX,
+            max_header_length: max_bytes,
+        };
+        let mut codec = Codec::with_limits(limits).for_sent_frames();
+        let mut frames = Vec::new();
+        let mut valid = 0;
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(Transmission::CompleteFrame(frame))) => frames.push(frame),
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt spooled message at byte {}: {}", valid, e),
+                    ));
+                }
+            }
+            valid = length - buffer.len() as u64;
+        }
+        if valid < length {
+            warn!(
+                "Truncating {} bytes of an incomplete message from {}",
+                length - valid,
+                self.config.path.display()
+            );
+            self.file.set_len(valid)?;
+        }
+        self.messages = frames.len();
+        self.bytes = valid;
+        Ok(frames)
+    }
+
+    // The messages which the broker has not confirmed, oldest first. Those it
+    // has confirmed are removed from the file.
+    pub fn pending(&mut self) -> io::Result<Vec<Frame>> {
+        let mut frames = self.frames()?;
+        self.sent.clear();
+        if self.confirmed > 0 {
+            frames.drain(..self.confirmed.min(frames.len()));
+            self.compact(&frames)?;
+        }
+        Ok(frames)
+    }
+
+    // Replaces the file with one holding only `frames`, so that a crash
+    // leaves either of them
+    fn compact(&mut self, frames: &[Frame]) -> io::Result<()> {
+        let mut encoded = BytesMut::new();
+        for frame in frames {
+            frame.write(&mut encoded);
+        }
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(".tmp");
+        let mut file = File::create(&path)?;
+        file.write_all(&encoded)?;
+        file.sync_data()?;
+        fs::rename(&path, &self.config.path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.messages = frames.len();
+        self.bytes = encoded.len() as u64;
+        self.confirmed = 0;
+        Ok(())
+    }
+
+    // Notes that a spooled message was sent requesting the receipt
+    // `receipt_id`
+    pub fn sent(&mut self, receipt_id: String) {
+        self.sent.push_back(receipt_id);
+    }
+
+    // Notes that the broker has processed the frame which requested the
+    // receipt `receipt_id`, and so every spooled message sent before it. The
+    // file is emptied once every message is confirmed.
+    pub fn confirm(&mut self, receipt_id: &str) -> io::Result<()> {
+        let count = match self.sent.iter().position(|sent| sent == receipt_id) {
+            Some(position) => position + 1,
+            None => return Ok(()),
+        };
+        self.sent.drain(..count);
+        self.confirmed += count;
+        if self.confirmed >= self.messages {
+            self.clear()?;
+        }
+        Ok(())
+    }
+
+    // Forgets the messages sent on a lost connection, whose receipts can no
+    // longer arrive
+    pub fn disconnected(&mut self) {
+        self.sent.clear();
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.messages = 0;
+        self.bytes = 0;
+        self.sent.clear();
+        self.confirmed = 0;
+        Ok(())
+    }
+}
+
+#[test]
+fn recover_spooled_messages() {
+    let path = ::std::env::temp_dir().join(format!("stomp-spool-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let config = Spool::new(&path).max_messages(2).sync(false);
+
+    let mut spool = SpoolFile::open(config.clone()).unwrap();
+    let mut one = Frame::send("/queue/a", b"one");
+    one.headers
+        .push(crate::header::Header::new("receipt", "message/0"));
+    spool.append(&one).unwrap();
+    spool.append(&Frame::send("/queue/a", b"two\0")).unwrap();
+    assert!(spool.append(&Frame::send("/queue/a", b"three")).is_err());
+    // A message torn by a crash
+    spool
+        .file
+        .write_all(b"SEND\ndestination:/queue/a\ncont")
+        .unwrap();
+    drop(spool);
+
+    let mut spool = SpoolFile::open(config).unwrap();
+    assert_eq!(spool.len(), 2);
+    let frames = spool.frames().unwrap();
+    // The receipt was requested by the previous session
+    assert!(frames.iter().all(|f| f.headers.get_receipt().is_none()));
+    let bodies: Vec<Vec<u8>> = frames.into_iter().map(|f| f.body).collect();
+    assert_eq!(bodies, vec![b"one".to_vec(), b"two\0".to_vec()]);
+    assert!(spool.append(&Frame::send("/queue/a", b"three")).is_err());
+    spool.clear().unwrap();
+    assert!(spool.is_empty());
+    assert_eq!(::std::fs::metadata(&path).unwrap().len(), 0);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn resend_spooled_messages_lost_while_draining() {
+    use crate::session::SessionEvent;
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, connected, run_until, Client};
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    let path = ::std::env::temp_dir().join(format!("stomp-drain-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let sent: Vec<Frame> = (0..3).map(|_| client.read()).collect();
+        client.receipt(&sent[0]);
+        // The connection is lost before the other messages are confirmed
+        drop(client);
+        let mut client = Client::connect(&listener);
+        let resent: Vec<Frame> = (0..2).map(|_| client.read()).collect();
+        client.receipt(&resent[1]);
+        client.quiet(Duration::from_millis(100));
+        resent.into_iter().map(|f| f.body).collect::<Vec<_>>()
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(Spool::new(&path).sync(false))
+        .start(core.handle())
+        .unwrap();
+    for body in &["one", "two", "three"] {
+        session.message("/queue/a", *body).send();
+    }
+    assert_eq!(session.spooled(), 3);
+    run_until(&mut core, &mut session, |_, events| {
+        events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Disconnected(_)))
+    });
+    assert_eq!(session.spooled(), 2);
+    assert!(::std::fs::metadata(&path).unwrap().len() > 0);
+    run_until(&mut core, &mut session, |session, _| {
+        session.reconnect().unwrap();
+        true
+    });
+    run_until(&mut core, &mut session, |session, events| {
+        events.iter().any(connected) && session.spooled() == 0
+    });
+    assert_eq!(::std::fs::metadata(&path).unwrap().len(), 0);
+    assert_eq!(
+        broker.join().unwrap(),
+        vec![b"two".to_vec(), b"three".to_vec()]
+    );
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn send_after_spooled_messages_until_connected() {
+    use crate::session_builder::SessionBuilder;
+    use crate::test_broker::{broker, run_until, Client};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    let path = ::std::env::temp_dir().join(format!("stomp-connect-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let config = Spool::new(&path).sync(false);
+    let mut spool = SpoolFile::open(config.clone()).unwrap();
+    spool.append(&Frame::send("/queue/a", b"one")).unwrap();
+    spool.append(&Frame::send("/queue/a", b"two")).unwrap();
+    drop(spool);
+
+    let (connecting, connect_read) = mpsc::channel();
+    let (sent, message_sent) = mpsc::channel();
+    let (port, broker) = broker(move |listener| {
+        let mut client = Client::accept(&listener);
+        client.read();
+        connecting.send(()).unwrap();
+        message_sent.recv().unwrap();
+        client.write("CONNECTED\nversion:1.2\n\n\0");
+        let frames: Vec<Frame> = (0..3).map(|_| client.read()).collect();
+        for frame in &frames {
+            client.receipt(frame);
+        }
+        client.quiet(Duration::from_millis(100));
+        frames.into_iter().map(|f| f.body).collect::<Vec<_>>()
+    });
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .with(config)
+        .start(core.handle())
+        .unwrap();
+    // Connected to the broker, which has not answered CONNECT yet
+    run_until(&mut core, &mut session, |_, _| {
+        connect_read.try_recv().is_ok()
+    });
+    session.message("/queue/a", "three").send();
+    assert_eq!(session.spooled(), 3);
+    sent.send(()).unwrap();
+    run_until(&mut core, &mut session, |session, _| session.spooled() == 0);
+    assert_eq!(
+        broker.join().unwrap(),
+        vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+    );
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn keep_spooled_messages_which_cannot_be_read() {
+    let path = ::std::env::temp_dir().join(format!("stomp-corrupt-{}", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let config = Spool::new(&path).sync(false);
+
+    let mut spool = SpoolFile::open(config.clone()).unwrap();
+    // Longer than the header lines of frames received from a broker may be
+    let mut large = Frame::send("/queue/a", b"large");
+    large.headers.push(crate::header::Header::new(
+        "x-trace",
+        &"t".repeat(70 * 1024),
+    ));
+    spool.append(&large).unwrap();
+    spool.append(&Frame::send("/queue/a", b"small")).unwrap();
+    drop(spool);
+
+    let mut spool = SpoolFile::open(config.clone()).unwrap();
+    let bodies: Vec<Vec<u8>> = spool
+        .frames()
+        .unwrap()
+        .into_iter()
+        .map(|f| f.body)
+        .collect();
+    assert_eq!(bodies, vec![b"large".to_vec(), b"small".to_vec()]);
+    spool.file.write_all(b"BOGUS\n\n\0").unwrap();
+    let length = ::std::fs::metadata(&path).unwrap().len();
+    drop(spool);
+
+    assert!(SpoolFile::open(config).is_err());
+    assert_eq!(::std::fs::metadata(&path).unwrap().len(), length);
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -1,4 +1,8 @@
+use crate::dedup::SeenMessages;
+use crate::destination::Destination;
 use crate::header::HeaderList;
+use crate::redelivery::RedeliveryPolicy;
+use crate::selector::Filter;
 
 #[derive(Copy, Clone)]
 pub enum AckMode {
@@ -17,6 +21,21 @@ impl AckMode {
     }
 }
 
+/// Starts a subscription with this id rather than a generated one, so that
+/// it can be resumed by another connection, e.g. as a durable subscription.
+pub struct SubscriptionId<'a>(pub &'a str);
+
+/// What `Session::unsubscribe_with` does to a durable subscription.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unsubscribe {
+    /// Stops receiving messages. The broker keeps a durable subscription and
+    /// stores its messages until it is resumed.
+    Detach,
+    /// Ends the subscription, deleting a durable subscription and any
+    /// messages stored for it.
+    Destroy,
+}
+
 #[derive(Clone, Copy)]
 pub enum AckOrNack {
     Ack,
@@ -25,18 +44,36 @@ pub enum AckOrNack {
 
 pub struct Subscription {
     pub id: String,
-    pub destination: String,
+    pub destination: Destination,
     pub ack_mode: AckMode,
     pub headers: HeaderList,
+    /// Evaluated against each message, dropping those it does not match.
+    pub filter: Option<Filter>,
+    /// For a durable subscription, the headers which make an UNSUBSCRIBE
+    /// frame destroy it rather than detach from it.
+    pub durable: Option<HeaderList>,
+    /// Applied by `Session::message_failed`.
+    pub redelivery: Option<RedeliveryPolicy>,
+    /// The recent messages, if duplicates are dropped.
+    pub(crate) seen: Option<SeenMessages>,
 }
 
 impl Subscription {
-    pub fn new(id: u32, destination: &str, ack_mode: AckMode, headers: HeaderList) -> Subscription {
+    pub fn new(
+        id: u32,
+        destination: Destination,
+        ack_mode: AckMode,
+        headers: HeaderList,
+    ) -> Subscription {
         Subscription {
             id: format!("stomp-rs/{}", id),
-            destination: destination.to_string(),
+            destination,
             ack_mode,
             headers,
+            filter: None,
+            durable: None,
+            redelivery: None,
+            seen: None,
         }
     }
 }
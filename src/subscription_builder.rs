@@ -1,55 +1,115 @@
+use crate::consumer::{Consumer, HandlerFuture};
+use crate::dedup::{Deduplicate, SeenMessages};
+use crate::destination::Destination;
 use crate::frame::Frame;
 use crate::header::HeaderList;
 use crate::option_setter::OptionSetter;
+use crate::redelivery::RedeliveryPolicy;
+use crate::request::RequestHandler;
+use crate::selector::Filter;
 use crate::session::{OutstandingReceipt, ReceiptRequest, Session};
 use crate::subscription::{AckMode, Subscription};
+use futures::{Future, IntoFuture};
+use std::fmt;
 
 pub struct SubscriptionBuilder<'a> {
     pub session: &'a mut Session,
-    pub destination: String,
+    pub destination: Destination,
     pub ack_mode: AckMode,
     pub headers: HeaderList,
     pub receipt_request: Option<ReceiptRequest>,
+    pub filter: Option<Filter>,
+    pub id: Option<String>,
+    pub durable: Option<HeaderList>,
+    pub redelivery: Option<RedeliveryPolicy>,
+    pub dedup: Option<Deduplicate>,
+    pub(crate) consumer: Option<Consumer>,
+    pub(crate) request_handler: Option<RequestHandler>,
 }
 
 impl<'a> SubscriptionBuilder<'a> {
-    pub fn new(session: &'a mut Session, destination: String) -> Self {
+    pub fn new(session: &'a mut Session, destination: Destination) -> Self {
         SubscriptionBuilder {
             session,
             destination,
             ack_mode: AckMode::Auto,
             headers: HeaderList::new(),
             receipt_request: None,
+            filter: None,
+            id: None,
+            durable: None,
+            redelivery: None,
+            dedup: None,
+            consumer: None,
+            request_handler: None,
         }
     }
 
+    /// Starts the subscription, returning its id. No subscription is started
+    /// if the SUBSCRIBE frame is dropped by an interceptor or rejected with a
+    /// `SessionEvent::FrameRejected`.
     #[allow(dead_code)]
     pub fn start(mut self) -> String {
+        // Failed messages are NACKed or acknowledged one at a time
+        if let (Some(_), AckMode::Client) = (&self.redelivery, self.ack_mode) {
+            self.ack_mode = AckMode::ClientIndividual;
+        }
         let next_id = self.session.generate_subscription_id();
-        let subscription = Subscription::new(
+        let mut subscription = Subscription::new(
             next_id,
-            &self.destination,
+            self.destination.clone(),
             self.ack_mode,
             self.headers.clone(),
         );
+        if let Some(id) = self.id.take() {
+            subscription.id = id;
+        }
+        subscription.filter = self.filter.take();
+        subscription.durable = self.durable.take();
+        subscription.redelivery = self.redelivery.take();
+        subscription.seen = self.dedup.take().map(SeenMessages::new);
         let mut subscribe_frame =
             Frame::subscribe(&subscription.id, &self.destination, self.ack_mode);
 
         subscribe_frame.headers.concat(&mut self.headers);
 
-        self.session.send_frame(subscribe_frame.clone());
+        if self
+            .session
+            .state
+            .subscriptions
+            .contains_key(&subscription.id)
+        {
+            warn!(
+                "Replacing subscription '{}', whose id was reused",
+                subscription.id
+            );
+        }
+        let id_to_return = subscription.id.to_string();
+        if !self.session.try_send_frame(subscribe_frame.clone()) {
+            return id_to_return;
+        }
 
         debug!(
             "Registering callback for subscription id '{}' from builder",
             subscription.id
         );
-        let id_to_return = subscription.id.to_string();
         self.session
             .state
             .subscriptions
             .insert(subscription.id.to_string(), subscription);
-        if self.receipt_request.is_some() {
-            let request = self.receipt_request.unwrap();
+        if let Some(consumer) = self.consumer.take() {
+            self.session
+                .state
+                .consumers
+                .insert(id_to_return.clone(), consumer);
+        }
+        if let Some(handler) = self.request_handler.take() {
+            self.session
+                .state
+                .request_handlers
+                .insert(id_to_return.clone(), handler);
+        }
+        if let Some(request) = self.receipt_request {
             self.session
                 .state
                 .outstanding_receipts
@@ -58,6 +118,50 @@ impl<'a> SubscriptionBuilder<'a> {
         id_to_return
     }
 
+    /// Starts the subscription, handling its messages with `handler` rather
+    /// than delivering them as events, with at most `concurrency` handlers
+    /// running at a time. A message is acknowledged once its handler
+    /// succeeds, and passed to `Session::message_failed` if it fails. As
+    /// handlers finish out of order, `AckMode::Client` is replaced by
+    /// `AckMode::ClientIndividual`. No more frames are read while
+    /// `concurrency` messages are waiting for a handler; the broker's own
+    /// prefetch option, such as `activemq::PrefetchCount`, limits how many
+    /// unacknowledged messages it sends meanwhile. Returns the subscription
+    /// id.
+    pub fn consume<H, F>(mut self, concurrency: usize, mut handler: H) -> String
+    where
+        H: FnMut(&Frame) -> F + 'static,
+        F: IntoFuture<Item = ()>,
+        F::Future: 'static,
+        F::Error: fmt::Display,
+    {
+        if let AckMode::Client = self.ack_mode {
+            self.ack_mode = AckMode::ClientIndividual;
+        }
+        let handler = move |frame: &Frame| -> HandlerFuture {
+            Box::new(
+                handler(frame)
+                    .into_future()
+                    .map_err(|error| error.to_string()),
+            )
+        };
+        self.consumer = Some(Consumer::new(concurrency, Box::new(handler)));
+        self.start()
+    }
+
+    /// Starts the subscription, answering each request arriving there with
+    /// the reply returned by `handler`, typically built with `Frame::reply`.
+    /// These requests are not delivered as events, and are acknowledged once
+    /// answered unless the subscription uses `AckMode::Auto`. Returns the
+    /// subscription id.
+    pub fn serve<F>(mut self, handler: F) -> String
+    where
+        F: FnMut(&Frame) -> Option<Frame> + 'static,
+    {
+        self.request_handler = Some(Box::new(handler));
+        self.start()
+    }
+
     #[allow(dead_code)]
     pub fn with<T>(self, option_setter: T) -> SubscriptionBuilder<'a>
     where
@@ -66,3 +170,29 @@ impl<'a> SubscriptionBuilder<'a> {
         option_setter.set_option(self)
     }
 }
+
+#[test]
+fn ignore_rejected_subscription() {
+    use crate::session::SessionEvent;
+    use crate::test_broker::{broker, connected_session, header_value, run_until, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let subscribe = client.read();
+        assert_eq!(header_value(&subscribe, "destination"), "/queue/a");
+    });
+    let (mut core, mut session) = connected_session(port);
+
+    let rejected = session
+        .subscription("/queue/")
+        .consume(1, |_| Ok::<(), String>(()));
+    session.subscription("/queue/a").start();
+    run_until(&mut core, &mut session, |_, events| {
+        events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::FrameRejected { .. }))
+    });
+    assert!(!session.state.subscriptions.contains_key(&rejected));
+    assert!(!session.state.consumers.contains_key(&rejected));
+    broker.join().unwrap();
+}
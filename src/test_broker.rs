@@ -0,0 +1,195 @@
+// A scripted broker for tests which drive a `Session` over a real connection.
+// The script runs on its own thread, reading the frames the session sends and
+// writing the broker's frames by hand.
+use crate::codec::Codec;
+use crate::frame::{Command, Frame, Transmission};
+use crate::session::{Session, SessionEvent};
+use crate::session_builder::SessionBuilder;
+use bytes::BytesMut;
+use futures::{future, Async, Future, Stream};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Core, Timeout};
+use tokio_io::codec::Decoder;
+
+// How long a test may wait for the session or the broker
+const TIMEOUT: Duration = Duration::from_secs(10);
+// How often the session is polled while waiting for the broker
+const TICK: Duration = Duration::from_millis(5);
+
+// Runs `script` on a listener for the session to connect to, returning the
+// listener's port and the script's thread.
+pub fn broker<F, T>(script: F) -> (u16, JoinHandle<T>)
+where
+    F: FnOnce(TcpListener) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    (port, thread::spawn(move || script(listener)))
+}
+
+// The broker's end of a connection from the session
+pub struct Client {
+    stream: TcpStream,
+    buffer: BytesMut,
+    codec: Codec,
+}
+
+impl Client {
+    pub fn accept(listener: &TcpListener) -> Client {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Client {
+            stream,
+            buffer: BytesMut::new(),
+            codec: Codec::new().for_sent_frames(),
+        }
+    }
+
+    // Accepts a connection and answers its CONNECT frame
+    pub fn connect(listener: &TcpListener) -> Client {
+        let mut client = Client::accept(listener);
+        let connect = client.read();
+        assert!(matches!(connect.command, Command::Connect));
+        client.write("CONNECTED\nversion:1.2\n\n\0");
+        client
+    }
+
+    // The next frame sent by the session, skipping heartbeats
+    pub fn read(&mut self) -> Frame {
+        loop {
+            match self.codec.decode(&mut self.buffer).unwrap() {
+                Some(Transmission::CompleteFrame(frame)) => return frame,
+                Some(_) => continue,
+                None => {}
+            }
+            let mut chunk = [0; 4096];
+            let count = self.stream.read(&mut chunk).expect("no frame arrived");
+            assert!(count > 0, "the session closed the connection");
+            self.buffer.extend_from_slice(&chunk[..count]);
+        }
+    }
+
+    pub fn write(&mut self, frame: &str) {
+        self.stream.write_all(frame.as_bytes()).unwrap();
+    }
+
+    // Answers the receipt requested by `frame`
+    pub fn receipt(&mut self, frame: &Frame) {
+        let receipt_id = header_value(frame, "receipt");
+        self.write(&format!("RECEIPT\nreceipt-id:{}\n\n\0", receipt_id));
+    }
+
+    // Refuses `frame`, which requested a receipt
+    pub fn refuse(&mut self, frame: &Frame) {
+        let receipt_id = header_value(frame, "receipt");
+        self.write(&format!(
+            "ERROR\nreceipt-id:{}\nmessage:refused\n\n\0",
+            receipt_id
+        ));
+    }
+
+    pub fn message(&mut self, subscription: &str, message_id: &str, body: &str) {
+        self.write(&format!(
+            "MESSAGE\nsubscription:{}\nmessage-id:{}\nack:{}\ndestination:/queue/a\n\n{}\0",
+            subscription, message_id, message_id, body
+        ));
+    }
+
+    // Checks that the session sends nothing for `period`
+    pub fn quiet(&mut self, period: Duration) {
+        assert!(self.buffer.is_empty(), "the session sent another frame");
+        self.stream.set_read_timeout(Some(period)).unwrap();
+        let mut chunk = [0; 4096];
+        match self.stream.read(&mut chunk) {
+            Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            result => panic!("expected the session to send nothing, got {:?}", result),
+        }
+        self.stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    }
+
+    // Waits for the session to close the connection, checking that it sent
+    // nothing more
+    pub fn closed(mut self) {
+        let mut rest = Vec::new();
+        self.stream.read_to_end(&mut rest).unwrap();
+        assert!(self.buffer.is_empty() && rest.is_empty());
+    }
+}
+
+// The value of the header `key` of `frame`, which must have one
+pub fn header_value<'a>(frame: &'a Frame, key: &str) -> &'a str {
+    match frame.headers.get_header(key) {
+        Some(header) => header.get_value(),
+        None => panic!("{} frame has no '{}' header", frame.command, key),
+    }
+}
+
+// Whether `event` is `SessionEvent::Connected`
+pub fn connected(event: &SessionEvent) -> bool {
+    matches!(event, SessionEvent::Connected)
+}
+
+// Starts a session with the broker listening on `port`, and waits for it to
+// be connected
+pub fn connected_session(port: u16) -> (Core, Session) {
+    let mut core = Core::new().unwrap();
+    let mut session = SessionBuilder::new("127.0.0.1", port)
+        .start(core.handle())
+        .unwrap();
+    run_until(&mut core, &mut session, |_, events| {
+        events.iter().any(connected)
+    });
+    (core, session)
+}
+
+// Polls the session until `done` holds, returning the events it produced
+// meanwhile. Fails if that takes longer than `TIMEOUT`.
+pub fn run_until<F>(core: &mut Core, session: &mut Session, mut done: F) -> Vec<SessionEvent>
+where
+    F: FnMut(&mut Session, &[SessionEvent]) -> bool,
+{
+    let deadline = Instant::now() + TIMEOUT;
+    let mut tick = Timeout::new(TICK, &core.handle()).unwrap();
+    let mut events = Vec::new();
+    let polled = future::poll_fn(|| {
+        while let Async::Ready(Some(event)) = session.poll()? {
+            events.push(event);
+        }
+        if done(session, &events) {
+            return Ok(Async::Ready(()));
+        }
+        assert!(Instant::now() < deadline, "timed out polling the session");
+        // `done` may be waiting for the broker rather than for the session
+        while let Async::Ready(()) = tick.poll()? {
+            tick.reset(Instant::now() + TICK);
+        }
+        Ok::<_, crate::error::Error>(Async::NotReady)
+    });
+    core.run(polled).unwrap();
+    events
+}
+
+// Polls the session until `future` resolves, returning its result
+pub fn wait<F: Future>(
+    core: &mut Core,
+    session: &mut Session,
+    mut future: F,
+) -> Result<F::Item, F::Error> {
+    let mut result = None;
+    run_until(core, session, |_, _| match future.poll() {
+        Ok(Async::NotReady) => false,
+        Ok(Async::Ready(item)) => {
+            result = Some(Ok(item));
+            true
+        }
+        Err(e) => {
+            result = Some(Err(e));
+            true
+        }
+    });
+    result.unwrap()
+}
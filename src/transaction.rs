@@ -1,25 +1,54 @@
+use crate::destination::Destination;
+use crate::error::{Error, Result};
 use crate::frame::Frame;
 use crate::frame::ToFrameBody;
 use crate::header::Header;
 use crate::message_builder::MessageBuilder;
-use crate::session::Session;
+use crate::session::{ReceiptFuture, Session};
+use crate::subscription::AckOrNack;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use std::cell::RefCell;
+use std::cmp::min;
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::Timeout;
 
+/// The frames sent as part of a transaction.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionLog {
+    /// SEND frames, in the order they were sent.
+    pub messages: Vec<Frame>,
+    /// ACK and NACK frames, in the order they were sent.
+    pub acks: Vec<Frame>,
+}
+
+/// A transaction started with `Session::begin_transaction`. It is aborted
+/// when dropped unless `commit` or `abort` was called.
 pub struct Transaction<'tx> {
     pub id: String,
     pub session: &'tx mut Session,
+    finished: bool,
 }
 
 impl<'tx> Transaction<'tx> {
     pub fn new(session: &'tx mut Session) -> Transaction<'tx> {
+        let id = format!("tx/{}", session.generate_transaction_id());
+        session
+            .state
+            .transactions
+            .insert(id.clone(), TransactionLog::default());
         Transaction {
-            id: format!("tx/{}", session.generate_transaction_id()),
+            id,
             session,
+            finished: false,
         }
     }
 
-    pub fn message<'builder, T: ToFrameBody>(
+    pub fn message<'builder, D: Into<Destination>, T: ToFrameBody>(
         &'builder mut self,
-        destination: &str,
+        destination: D,
         body_convertible: T,
     ) -> MessageBuilder<'builder> {
         let mut send_frame = Frame::send(destination, body_convertible.to_frame_body());
@@ -29,6 +58,22 @@ impl<'tx> Transaction<'tx> {
         MessageBuilder::new(self.session, send_frame)
     }
 
+    /// Acknowledges a received message as part of this transaction. The
+    /// acknowledgement only takes effect if the transaction is committed.
+    pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) {
+        if let Some(mut ack_frame) = Frame::acknowledgement(frame, which) {
+            ack_frame
+                .headers
+                .push(Header::new("transaction", self.id.as_ref()));
+            self.session.send_frame(ack_frame)
+        }
+    }
+
+    /// The frames sent in this transaction so far.
+    pub fn log(&self) -> &TransactionLog {
+        &self.session.state.transactions[&self.id]
+    }
+
     // TODO: See if it's feasible to do this via command_sender
 
     pub fn begin(&mut self) {
@@ -36,13 +81,370 @@ impl<'tx> Transaction<'tx> {
         self.session.send_frame(begin_frame)
     }
 
-    pub fn commit(self) {
+    /// Commits the transaction, returning the frames it contained.
+    pub fn commit(mut self) -> TransactionLog {
         let commit_frame = Frame::commit(self.id.as_ref());
-        self.session.send_frame(commit_frame)
+        self.session.send_frame(commit_frame);
+        self.finish()
     }
 
-    pub fn abort(self) {
+    /// Commits the transaction, requesting a receipt for the COMMIT frame.
+    /// The returned future fails with the broker's ERROR if the commit was
+    /// refused.
+    pub fn commit_with_receipt(mut self) -> (TransactionLog, ReceiptFuture) {
+        let commit_frame = Frame::commit(self.id.as_ref());
+        let receipt = self.session.send_with_receipt(commit_frame);
+        (self.finish(), receipt)
+    }
+
+    /// Aborts the transaction, returning the frames which were undone.
+    pub fn abort(mut self) -> TransactionLog {
         let abort_frame = Frame::abort(self.id.as_ref());
-        self.session.send_frame(abort_frame)
+        self.session.send_frame(abort_frame);
+        self.finish()
+    }
+
+    fn finish(&mut self) -> TransactionLog {
+        self.finished = true;
+        self.session
+            .state
+            .transactions
+            .remove(&self.id)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            debug!("Transaction '{}' dropped, aborting it", self.id);
+            let abort_frame = Frame::abort(self.id.as_ref());
+            self.session.send_frame(abort_frame);
+            self.finish();
+        }
+    }
+}
+
+/// How `Session::transactional` retries a transaction whose commit was
+/// refused by the broker or lost with the connection.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The number of attempts made in total, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry. It doubles with each further retry.
+    pub backoff: Duration,
+    /// The longest delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a commit failing with `error` after `attempts` attempts should
+    /// be retried.
+    pub fn should_retry(&self, attempts: u32, error: &Error) -> bool {
+        let retriable = matches!(*error, Error::Broker(_) | Error::Disconnected);
+        retriable && attempts < self.max_attempts
+    }
+
+    /// The delay before the attempt following the `attempts`th one.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        min(
+            self.backoff.checked_mul(factor).unwrap_or(self.max_backoff),
+            self.max_backoff,
+        )
+    }
+}
+
+/// Resolves with the number of attempts it took once a transaction started
+/// by `Session::transactional` has been committed, or with the error which
+/// ended it. The `Session` must be polled for it to make progress.
+pub struct TransactionalFuture {
+    receiver: oneshot::Receiver<Result<u32>>,
+}
+
+impl Future for TransactionalFuture {
+    type Item = u32;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<u32, Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(Error::Disconnected),
+        }
+    }
+}
+
+/// The transaction of an attempt made by `Session::transactional`, which its
+/// unit of work can keep while it runs. The messages and acknowledgements
+/// sent through it are handed to the session when it is next polled.
+#[derive(Clone)]
+pub struct TransactionScope {
+    id: Rc<str>,
+    queued: Rc<RefCell<Vec<Frame>>>,
+}
+
+impl TransactionScope {
+    pub(crate) fn new(id: &str) -> TransactionScope {
+        TransactionScope {
+            id: id.into(),
+            queued: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sends `frame`, e.g. a SEND frame with custom headers, as part of this
+    /// transaction.
+    pub fn send_frame(&self, mut frame: Frame) {
+        frame.headers.push(Header::new("transaction", &self.id));
+        self.queued.borrow_mut().push(frame);
+    }
+
+    pub fn message<D: Into<Destination>, T: ToFrameBody>(&self, destination: D, body: T) {
+        self.send_frame(Frame::send(destination, body.to_frame_body()));
+    }
+
+    /// Acknowledges a received message as part of this transaction.
+    pub fn acknowledge_frame(&self, frame: &Frame, which: AckOrNack) {
+        if let Some(ack_frame) = Frame::acknowledgement(frame, which) {
+            self.send_frame(ack_frame);
+        }
+    }
+
+    // The frames sent through the scope since the last call
+    pub(crate) fn take_queued(&self) -> Vec<Frame> {
+        mem::take(&mut *self.queued.borrow_mut())
+    }
+}
+
+/// A unit of work run by `Session::transactional`, which fails with the
+/// reason the transaction has to be aborted.
+pub type WorkFuture = Box<dyn Future<Item = (), Error = Error>>;
+
+pub(crate) type TransactionalWork = Box<dyn FnMut(TransactionScope) -> WorkFuture>;
+
+pub(crate) enum JobState {
+    // Waiting for the session to be connected to make the next attempt
+    Ready,
+    Working(TransactionScope, WorkFuture),
+    Committing(ReceiptFuture),
+    BackingOff(Timeout),
+}
+
+// A transaction being run by `Session::transactional`.
+pub(crate) struct TransactionalJob {
+    pub work: TransactionalWork,
+    pub policy: RetryPolicy,
+    pub attempts: u32,
+    pub state: JobState,
+    result: oneshot::Sender<Result<u32>>,
+}
+
+impl TransactionalJob {
+    pub fn new(
+        work: TransactionalWork,
+        policy: RetryPolicy,
+    ) -> (TransactionalJob, TransactionalFuture) {
+        let (result, receiver) = oneshot::channel();
+        let job = TransactionalJob {
+            work,
+            policy,
+            attempts: 0,
+            state: JobState::Ready,
+            result,
+        };
+        (job, TransactionalFuture { receiver })
     }
+
+    pub fn finish(self, result: Result<u32>) {
+        let _ = self.result.send(result);
+    }
+}
+
+#[test]
+fn retry_policy_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 4,
+        backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(350),
+    };
+    assert_eq!(policy.backoff(1), Duration::from_millis(100));
+    assert_eq!(policy.backoff(2), Duration::from_millis(200));
+    assert_eq!(policy.backoff(3), Duration::from_millis(350));
+    assert_eq!(policy.backoff(40), Duration::from_millis(350));
+
+    assert!(policy.should_retry(3, &Error::Disconnected));
+    assert!(!policy.should_retry(4, &Error::Disconnected));
+    assert!(!policy.should_retry(1, &Error::InvalidUsage("failed".to_owned())));
+}
+
+#[test]
+fn acknowledge_within_a_transaction() {
+    use crate::session::SessionEvent;
+    use crate::subscription::AckMode;
+    use crate::test_broker::{broker, connected_session, header_value, run_until, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let subscribe = client.read();
+        let sub_id = header_value(&subscribe, "id").to_owned();
+        client.message(&sub_id, "m-1", "one");
+        client.message(&sub_id, "m-2", "two");
+        (0..4).map(|_| client.read()).collect::<Vec<_>>()
+    });
+    let (mut core, mut session) = connected_session(port);
+    session
+        .subscription("/queue/a")
+        .with(AckMode::ClientIndividual)
+        .start();
+    let messages: Vec<Frame> = run_until(&mut core, &mut session, |_, events| events.len() == 2)
+        .into_iter()
+        .map(|event| match event {
+            SessionEvent::Message { frame, .. } => frame,
+            _ => panic!("expected a message"),
+        })
+        .collect();
+
+    let mut transaction = session.begin_transaction();
+    let id = transaction.id.clone();
+    transaction.acknowledge_frame(&messages[0], AckOrNack::Ack);
+    transaction.acknowledge_frame(&messages[1], AckOrNack::Nack);
+    let log = transaction.commit();
+    let commands = |frames: &[Frame]| -> Vec<&str> {
+        frames.iter().map(|frame| frame.command.as_str()).collect()
+    };
+    assert_eq!(commands(&log.acks), vec!["ACK", "NACK"]);
+    assert!(log.messages.is_empty());
+
+    let sent = broker.join().unwrap();
+    assert_eq!(commands(&sent), vec!["BEGIN", "ACK", "NACK", "COMMIT"]);
+    for (frame, message_id) in sent[1..3].iter().zip(&["m-1", "m-2"]) {
+        assert_eq!(header_value(frame, "id"), *message_id);
+        assert_eq!(header_value(frame, "transaction"), id);
+    }
+}
+
+#[cfg(test)]
+const TEST_RETRIES: RetryPolicy = RetryPolicy {
+    max_attempts: 2,
+    backoff: Duration::from_millis(10),
+    max_backoff: Duration::from_millis(10),
+};
+
+#[test]
+fn abort_transaction_when_work_fails() {
+    use crate::test_broker::{broker, connected_session, header_value, wait, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        (0..3).map(|_| client.read()).collect::<Vec<_>>()
+    });
+    let (mut core, mut session) = connected_session(port);
+    let committed = session.transactional(TEST_RETRIES, |scope: TransactionScope| {
+        scope.message("/queue/a", "one");
+        Err(Error::InvalidUsage("failed".to_owned()))
+    });
+    match wait(&mut core, &mut session, committed) {
+        Err(Error::InvalidUsage(reason)) => assert_eq!(reason, "failed"),
+        _ => panic!("expected the work's error"),
+    }
+
+    let sent = broker.join().unwrap();
+    let commands: Vec<&str> = sent.iter().map(|frame| frame.command.as_str()).collect();
+    assert_eq!(commands, vec!["BEGIN", "SEND", "ABORT"]);
+    assert_eq!(header_value(&sent[1], "transaction"), "tx/0");
+    assert!(session.state.transactions.is_empty());
+}
+
+#[test]
+fn retry_transaction_refused_by_broker() {
+    use crate::test_broker::{broker, connected_session, wait, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let mut sent = Vec::new();
+        for attempt in 0..2 {
+            sent.extend((0..3).map(|_| client.read()));
+            let commit = sent.last().unwrap();
+            if attempt == 0 {
+                client.refuse(commit);
+            } else {
+                client.receipt(commit);
+            }
+        }
+        sent
+    });
+    let (mut core, mut session) = connected_session(port);
+    let handle = core.handle();
+    // The message is only sent once the unit of work has waited a little
+    let committed = session.transactional(TEST_RETRIES, move |scope: TransactionScope| {
+        Timeout::new(Duration::from_millis(10), &handle)
+            .unwrap()
+            .map_err(Error::from)
+            .map(move |()| scope.message("/queue/a", "one"))
+    });
+    assert_eq!(wait(&mut core, &mut session, committed).unwrap(), 2);
+
+    let sent = broker.join().unwrap();
+    let commands: Vec<&str> = sent.iter().map(|frame| frame.command.as_str()).collect();
+    assert_eq!(
+        commands,
+        vec!["BEGIN", "SEND", "COMMIT", "BEGIN", "SEND", "COMMIT"]
+    );
+}
+
+#[test]
+fn retry_transaction_after_reconnecting() {
+    use crate::session::SessionEvent;
+    use crate::test_broker::{broker, connected_session, run_until, wait, Client};
+
+    let (port, broker) = broker(|listener| {
+        let mut client = Client::connect(&listener);
+        let mut sent: Vec<Frame> = (0..3).map(|_| client.read()).collect();
+        // The connection is lost before the commit is confirmed
+        drop(client);
+        let mut client = Client::connect(&listener);
+        sent.extend((0..3).map(|_| client.read()));
+        client.receipt(sent.last().unwrap());
+        sent
+    });
+    let (mut core, mut session) = connected_session(port);
+    let mut committed = session.transactional(TEST_RETRIES, |scope: TransactionScope| {
+        scope.message("/queue/a", "one");
+        Ok(())
+    });
+    run_until(&mut core, &mut session, |_, events| {
+        events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Disconnected(_)))
+    });
+    // The retry waits for the session to reconnect
+    run_until(&mut core, &mut session, |session, _| {
+        assert!(committed.poll().unwrap().is_not_ready());
+        session.reconnect().unwrap();
+        true
+    });
+    assert_eq!(wait(&mut core, &mut session, committed).unwrap(), 2);
+
+    let sent = broker.join().unwrap();
+    let commands: Vec<&str> = sent.iter().map(|frame| frame.command.as_str()).collect();
+    assert_eq!(
+        commands,
+        vec!["BEGIN", "SEND", "COMMIT", "BEGIN", "SEND", "COMMIT"]
+    );
 }